@@ -0,0 +1,196 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde_derive::Deserialize;
+use tokio::time::sleep;
+
+use crate::config::TelegramSettings;
+use crate::helpers::{collapse_repeats, escape_message, format_duration, generate_messages};
+use crate::journal::read_unit_tail;
+use crate::mute;
+use crate::parser::parse_message;
+use crate::sink::SinkHandle;
+use crate::telegram::TelegramClient;
+use crate::template::LineFormat;
+
+const POLL_TIMEOUT: Duration = Duration::from_secs(50);
+const DEFAULT_TAIL_LINES: usize = 10;
+const MAX_TAIL_LINES: usize = 100;
+
+#[derive(Deserialize)]
+struct UpdatesResponse {
+	result: Vec<Update>,
+}
+
+#[derive(Deserialize)]
+struct Update {
+	update_id: i64,
+	message: Option<Message>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+	chat: Chat,
+	message_thread_id: Option<i64>,
+	text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Chat {
+	id: i64,
+}
+
+struct Bot {
+	authorised_chats: Vec<String>,
+	client: TelegramClient,
+	sinks: Arc<Vec<SinkHandle>>,
+	started: Instant,
+}
+
+/// Start long polling getUpdates and answering commands from authorised chats
+pub fn spawn(settings: &TelegramSettings, sinks: Arc<Vec<SinkHandle>>) {
+	let mut authorised_chats = vec![settings.chat_id.clone()];
+	authorised_chats.extend(settings.destinations.values().map(|destination| destination.chat_id.clone()));
+	authorised_chats.extend(settings.command_chats.iter().cloned());
+
+	let bot = Bot {
+		authorised_chats,
+		client: TelegramClient::new(settings),
+		sinks,
+		started: Instant::now(),
+	};
+
+	tokio::spawn(async move {
+		bot.poll().await;
+	});
+
+	println!("[bot] listening for commands");
+}
+
+impl Bot {
+	async fn poll(&self) {
+		let mut offset: i64 = 0;
+
+		loop {
+			let updates = match self.get_updates(offset).await {
+				Ok(updates) => updates,
+				Err(e) => {
+					eprintln!("[bot] Failed to get updates: {}", e);
+					sleep(Duration::from_secs(5)).await;
+					continue
+				}
+			};
+
+			for update in updates {
+				offset = offset.max(update.update_id + 1);
+				if let Some(message) = update.message {
+					self.handle_message(message).await;
+				}
+			}
+		}
+	}
+
+	async fn get_updates(&self, offset: i64) -> Result<Vec<Update>, String> {
+		let response = self.client.get_updates(offset, POLL_TIMEOUT).await
+			.map_err(|e| e.to_string())?;
+
+		let status = response.status();
+		let text = response.text().await.map_err(|e| e.to_string())?;
+		if !status.is_success() {
+			return Err(format!("API response {}: {:?}", status, text));
+		}
+
+		serde_json::from_str::<UpdatesResponse>(&text)
+			.map(|response| response.result)
+			.map_err(|e| e.to_string())
+	}
+
+	async fn handle_message(&self, message: Message) {
+		let text = match &message.text {
+			Some(text) if text.starts_with('/') => text,
+			_ => return,
+		};
+
+		let chat_id = message.chat.id.to_string();
+		if !self.authorised_chats.contains(&chat_id) {
+			println!("[bot] Ignoring command from unauthorised chat {}", chat_id);
+			return
+		}
+
+		let mut args = text.split_whitespace();
+		// commands in groups can be addressed as /command@botname
+		let command = args.next().unwrap_or("").split('@').next().unwrap_or("");
+		let args: Vec<&str> = args.collect();
+
+		let replies = match command {
+			"/status" => vec![self.status().await],
+			"/mute" | "/unmute" | "/mutes" => {
+				let reply = mute::run_command(&command[1..], &args).unwrap_or_else(|e| e);
+				vec![escape_message(&reply)]
+			},
+			"/tail" => command_tail(&args).await,
+			_ => vec![escape_message("Unknown command. Available: /status, /mute <identifier|FIELD=value|[FIELD=]~regex> <duration>, /unmute [rule], /mutes, /tail <unit> [n]")],
+		};
+
+		for reply in replies {
+			self.reply(&chat_id, message.message_thread_id, &reply).await;
+		}
+	}
+
+	async fn reply(&self, chat_id: &str, message_thread_id: Option<i64>, text: &str) {
+		let result = self.client.send_message(chat_id, message_thread_id, text, false).await;
+
+		match result {
+			Ok(response) if !response.status().is_success() => {
+				eprintln!("[bot] Failed to reply, API response {}", response.status());
+			},
+			Err(e) => eprintln!("[bot] Failed to reply: {}", e),
+			_ => {},
+		}
+	}
+
+	async fn status(&self) -> String {
+		let mut lines = vec![
+			format!("telelog v{}", env!("CARGO_PKG_VERSION")),
+			format!("Uptime: {}", format_duration(self.started.elapsed())),
+		];
+
+		for sink in self.sinks.iter() {
+			let status = sink.status().await;
+			lines.push(format!("{}: {} buffered, {} unsent, retry count {}", sink.name(), status.buffered, status.unsent, status.retry_count));
+		}
+
+		for mute in mute::active() {
+			lines.push(format!("Muted {} until {}", mute.key(), mute.until.format("%b %d %H:%M:%S")));
+		}
+
+		escape_message(&lines.join("\n"))
+	}
+}
+
+async fn command_tail(args: &[&str]) -> Vec<String> {
+	let unit = match args.first() {
+		Some(unit) if unit.contains('.') => unit.to_string(),
+		Some(unit) => format!("{}.service", unit),
+		None => return vec![escape_message("Usage: /tail <unit> [n]")],
+	};
+	let count = match args.get(1).map(|n| n.parse::<usize>()) {
+		Some(Ok(count)) => count.clamp(1, MAX_TAIL_LINES),
+		Some(Err(_)) => return vec![escape_message("Usage: /tail <unit> [n]")],
+		None => DEFAULT_TAIL_LINES,
+	};
+
+	let read_unit = unit.clone();
+	let records = match tokio::task::spawn_blocking(move || read_unit_tail(&read_unit, count)).await {
+		Ok(Ok(records)) => records,
+		Ok(Err(e)) => return vec![escape_message(&e)],
+		Err(e) => return vec![escape_message(&format!("Failed to read journal: {}", e))],
+	};
+
+	let entries: Vec<_> = records.into_iter().filter_map(|record| parse_message(record).ok()).collect();
+	if entries.is_empty() {
+		return vec![escape_message(&format!("No entries for {}", unit))];
+	}
+
+	generate_messages(&collapse_repeats(&entries, None), &LineFormat::default())
+}
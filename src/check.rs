@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use crate::config::{parse_config, AppSettings, JsonWebhookSettings, MatrixSettings, Rule, RuleValue, SmtpSettings, WebhookSettings};
+use crate::helpers::parse_duration;
+use crate::{discord, filter, matrix, slack, smtp, spool, telegram, webhook};
+use crate::filter::Rules;
+use crate::template::{parse_priority, LineFormat};
+
+/// Fields journald and its clients commonly write, see systemd.journal-fields(7),
+/// plus the shorthands `LogEntry::get_field` understands
+const KNOWN_FIELDS: &[&str] = &[
+	"MESSAGE", "MESSAGE_ID", "PRIORITY", "CODE_FILE", "CODE_LINE", "CODE_FUNC", "ERRNO",
+	"INVOCATION_ID", "USER_INVOCATION_ID", "SYSLOG_FACILITY", "SYSLOG_IDENTIFIER", "SYSLOG_PID",
+	"SYSLOG_TIMESTAMP", "SYSLOG_RAW", "DOCUMENTATION", "TID", "UNIT", "USER_UNIT",
+	"_PID", "_UID", "_GID", "_COMM", "_EXE", "_CMDLINE", "_CAP_EFFECTIVE", "_AUDIT_SESSION",
+	"_AUDIT_LOGINUID", "_SYSTEMD_CGROUP", "_SYSTEMD_SLICE", "_SYSTEMD_UNIT", "_SYSTEMD_USER_UNIT",
+	"_SYSTEMD_USER_SLICE", "_SYSTEMD_SESSION", "_SYSTEMD_OWNER_UID", "_SYSTEMD_INVOCATION_ID",
+	"_SELINUX_CONTEXT", "_SOURCE_REALTIME_TIMESTAMP", "_SOURCE_MONOTONIC_TIMESTAMP", "_BOOT_ID",
+	"_MACHINE_ID", "_HOSTNAME", "_TRANSPORT", "_STREAM_ID", "_LINE_BREAK", "_NAMESPACE",
+	"_RUNTIME_SCOPE", "_KERNEL_DEVICE", "_KERNEL_SUBSYSTEM", "_UDEV_SYSNAME", "_UDEV_DEVNODE",
+	"_UDEV_DEVLINK", "COREDUMP_UNIT", "COREDUMP_USER_UNIT", "OBJECT_PID", "OBJECT_UID", "OBJECT_GID",
+	"OBJECT_COMM", "OBJECT_EXE", "OBJECT_CMDLINE", "OBJECT_AUDIT_SESSION", "OBJECT_AUDIT_LOGINUID",
+	"OBJECT_SYSTEMD_CGROUP", "OBJECT_SYSTEMD_SESSION", "OBJECT_SYSTEMD_OWNER_UID",
+	"OBJECT_SYSTEMD_UNIT", "OBJECT_SYSTEMD_USER_UNIT", "__CURSOR", "__REALTIME_TIMESTAMP",
+	"__MONOTONIC_TIMESTAMP", "__SEQNUM", "__SEQNUM_ID",
+	"TIMESTAMP", "IDENTIFIER",
+];
+
+#[derive(Default)]
+struct Report {
+	errors: Vec<String>,
+	warnings: Vec<String>,
+}
+
+/// journald only accepts field names made of uppercase letters, digits and underscores,
+/// not starting with a digit
+fn is_valid_field_name(field: &str) -> bool {
+	!field.is_empty()
+		&& !field.starts_with(|c: char| c.is_ascii_digit())
+		&& field.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
+
+fn values(rule: &Rule) -> Vec<&String> {
+	match &rule.value {
+		RuleValue::Single(value) => vec![value],
+		RuleValue::Multiple(values) => values.iter().collect(),
+	}
+}
+
+fn check_group_table(table: &str, groups: &Option<HashMap<u32, Vec<Rule>>>, destinations: &[String], report: &mut Report) {
+	let groups = match groups {
+		Some(groups) => groups,
+		None => return,
+	};
+
+	let mut priorities: Vec<&u32> = groups.keys().collect();
+	priorities.sort();
+
+	for priority in priorities {
+		check_group(table, *priority, &groups[priority], destinations, report);
+	}
+}
+
+fn check_group(table: &str, group: impl Display, rules: &[Rule], destinations: &[String], report: &mut Report) {
+	for (index, rule) in rules.iter().enumerate() {
+		let location = format!("[{}] group {}, rule {}, field {}", table, group, index + 1, rule.field);
+
+		if !is_valid_field_name(&rule.field) {
+			report.errors.push(format!("{}: invalid journal field name", location));
+		} else if !KNOWN_FIELDS.contains(&rule.field.as_str()) {
+			report.warnings.push(format!("{}: unknown journal field", location));
+		}
+
+		let rule_values = values(rule);
+		if rule_values.is_empty() {
+			report.errors.push(format!("{}: no values given", location));
+		}
+
+		for destination in rule.destinations.iter() {
+			if table == "deny" {
+				report.warnings.push(format!("{}: destination '{}' is ignored on deny rules", location, destination));
+			} else if table == "threshold" || table == "heartbeat" {
+				report.warnings.push(format!("{}: destination '{}' is ignored, set destinations on the {} itself", location, destination, table));
+			} else if !destinations.contains(destination) {
+				report.errors.push(format!("{}: unknown destination '{}'", location, destination));
+			}
+		}
+	}
+}
+
+fn check_thresholds(settings: &AppSettings, destinations: &[String], report: &mut Report) {
+	let mut priorities: Vec<&u32> = settings.threshold_rules.keys().collect();
+	priorities.sort();
+
+	for priority in priorities {
+		let threshold = &settings.threshold_rules[priority];
+		let location = format!("[threshold] group {}", priority);
+
+		if threshold.rules.is_empty() {
+			report.errors.push(format!("{}: no rules given, it would count every entry", location));
+		}
+		if threshold.count == 0 {
+			report.errors.push(format!("{}: count must be at least 1", location));
+		}
+		if let Some(key) = &threshold.key {
+			if !is_valid_field_name(key) {
+				report.errors.push(format!("{}: invalid key field name '{}'", location, key));
+			}
+		}
+		for destination in threshold.destinations.iter() {
+			if !destinations.contains(destination) {
+				report.errors.push(format!("{}: unknown destination '{}'", location, destination));
+			}
+		}
+
+		check_group("threshold", *priority, &threshold.rules, destinations, report);
+	}
+}
+
+fn check_heartbeats(settings: &AppSettings, destinations: &[String], report: &mut Report) {
+	let mut names: Vec<&String> = settings.heartbeat.rules.keys().collect();
+	names.sort();
+
+	for name in names {
+		let heartbeat = &settings.heartbeat.rules[name];
+		let location = format!("[heartbeat.{}]", name);
+
+		if heartbeat.rules.is_empty() {
+			report.errors.push(format!("{}: no rules given", location));
+		}
+		if parse_duration(&heartbeat.every).is_none() {
+			report.errors.push(format!("{}: invalid interval '{}', use e.g. 30m or 26h", location, heartbeat.every));
+		}
+		for destination in heartbeat.destinations.iter() {
+			if !destinations.contains(destination) {
+				report.errors.push(format!("{}: unknown destination '{}'", location, destination));
+			}
+		}
+
+		Rules::compile(&location, &heartbeat.rules, &mut report.errors);
+		check_group("heartbeat", name, &heartbeat.rules, destinations, report);
+	}
+
+	// [match] groups are applied by journald, anything they don't select never reaches a heartbeat
+	if !settings.heartbeat.rules.is_empty() && settings.match_rules.as_ref().is_some_and(|groups| !groups.is_empty()) {
+		report.warnings.push("[heartbeat] entries must also be selected by a [match] group to be seen".to_string());
+	}
+}
+
+/// Placeholders `LineFormat` fills in besides journal fields
+const LINE_PLACEHOLDERS: &[&str] = &["emoji", "priority", "priority_name", "timestamp", "identifier", "message", "count"];
+const BATCH_PLACEHOLDERS: &[&str] = &["count", "first", "last", "hostname"];
+
+fn check_format(settings: &AppSettings, destinations: &[String], report: &mut Report) {
+	let format = &settings.format;
+
+	let mut names: Vec<&String> = format.destinations.keys().collect();
+	names.sort();
+	for name in names.iter() {
+		if !destinations.contains(name) {
+			report.errors.push(format!("[format.destinations.{}]: unknown destination", name));
+		}
+	}
+
+	let priority_tables = std::iter::once(("[format.priority]".to_string(), &format.priority))
+		.chain(names.iter().map(|name| (format!("[format.destinations.{}.priority]", name), &format.destinations[*name].priority)));
+	for (location, marks) in priority_tables {
+		let mut levels: Vec<u8> = marks.keys().filter_map(|level| parse_priority(level).ok()).collect();
+		levels.sort();
+		for pair in levels.windows(2).filter(|pair| pair[0] == pair[1]) {
+			report.warnings.push(format!("{}: priority {} is set more than once, by its level and its name", location, pair[0]));
+		}
+	}
+
+	for (location, destination) in std::iter::once(("[format]".to_string(), "")).chain(names.iter().map(|name| (format!("[format.destinations.{}]", name), name.as_str()))) {
+		let line_format = match LineFormat::new(format, destination) {
+			Ok(line_format) => line_format,
+			Err(e) => {
+				report.errors.push(format!("{}: {}", location, e));
+				continue
+			}
+		};
+
+		for placeholder in line_format.template.placeholders() {
+			if !LINE_PLACEHOLDERS.contains(&placeholder) && !is_valid_field_name(placeholder) {
+				report.warnings.push(format!("{} template: '{{{}}}' is neither a journal field nor a known placeholder", location, placeholder));
+			}
+		}
+		for template in line_format.header.iter().chain(line_format.footer.iter()) {
+			for placeholder in template.placeholders() {
+				if !BATCH_PLACEHOLDERS.contains(&placeholder) {
+					report.warnings.push(format!("{} header/footer: unknown placeholder '{{{}}}'", location, placeholder));
+				}
+			}
+		}
+	}
+}
+
+/// Check the webhook URLs of a `[slack]` or `[discord]` section, and that its destination names are not taken
+fn check_webhooks(section: &str, settings: &WebhookSettings, names: Vec<String>, destinations: &mut Vec<String>, report: &mut Report) {
+	let urls = settings.webhook_url.iter().map(|url| (format!("[{}]", section), url))
+		.chain(settings.destinations.iter().map(|(name, destination)| (format!("[{}.destinations.{}]", section, name), &destination.webhook_url)));
+	for (location, url) in urls {
+		if !url.starts_with("http://") && !url.starts_with("https://") {
+			report.errors.push(format!("{} webhook_url: '{}' is not an http:// or https:// URL", location, url));
+		}
+	}
+
+	claim_names(section, names, destinations, report);
+}
+
+/// Add a section's destination names to the known ones, each name may only be used once
+fn claim_names(section: &str, names: Vec<String>, destinations: &mut Vec<String>, report: &mut Report) {
+	for name in names {
+		if !spool::is_valid_name(&name) {
+			report.errors.push(format!("[{}] destination '{}': names can't be empty or contain '/' or '..', they name spool files", section, name));
+		}
+		if destinations.contains(&name) {
+			report.errors.push(format!("[{}] destination '{}' is already used by another destination", section, name));
+		}
+		destinations.push(name);
+	}
+}
+
+fn check_matrix(settings: &MatrixSettings, destinations: &mut Vec<String>, report: &mut Report) {
+	let homeserver = match &settings.homeserver {
+		Some(homeserver) => homeserver,
+		None => {
+			if settings.room_id.is_some() || !settings.destinations.is_empty() {
+				report.errors.push("[matrix] rooms are configured, but no homeserver".to_string());
+			}
+			return
+		}
+	};
+
+	if !homeserver.starts_with("http://") && !homeserver.starts_with("https://") {
+		report.errors.push(format!("[matrix] homeserver: '{}' is not an http:// or https:// URL", homeserver));
+	}
+	if settings.access_token.is_none() && std::env::var("MATRIX_ACCESS_TOKEN").is_err() {
+		report.warnings.push("[matrix] no access_token set, and no MATRIX_ACCESS_TOKEN environment variable".to_string());
+	}
+
+	let rooms = settings.room_id.iter().map(|room_id| ("[matrix]".to_string(), room_id))
+		.chain(settings.destinations.iter().map(|(name, destination)| (format!("[matrix.destinations.{}]", name), &destination.room_id)));
+	for (location, room_id) in rooms {
+		if !room_id.starts_with('!') || !room_id.contains(':') {
+			report.errors.push(format!("{} room_id: '{}' is not a room ID like !abc123:example.org", location, room_id));
+		}
+	}
+
+	claim_names("matrix", matrix::destination_names(settings), destinations, report);
+}
+
+fn check_smtp(settings: &SmtpSettings, destinations: &mut Vec<String>, report: &mut Report) {
+	if settings.host.is_none() {
+		if !settings.to.is_empty() || !settings.destinations.is_empty() {
+			report.errors.push("[smtp] recipients are configured, but no host".to_string());
+		}
+		return
+	}
+
+	let security = match settings.security.as_deref().map(smtp::Security::parse).transpose() {
+		Ok(security) => {
+			if let Some(Err(e)) = settings.host.as_deref().map(|host| smtp::transport(settings, host)) {
+				report.errors.push(format!("[smtp] host: {}", e));
+			}
+			security.unwrap_or(smtp::Security::StartTls)
+		},
+		Err(e) => {
+			report.errors.push(format!("[smtp] security: {}", e));
+			smtp::Security::StartTls
+		}
+	};
+	match (&settings.username, &settings.password) {
+		(Some(_), None) if std::env::var("SMTP_PASSWORD").is_err() => {
+			report.warnings.push("[smtp] username set, but no password and no SMTP_PASSWORD environment variable".to_string());
+		},
+		(None, Some(_)) => report.warnings.push("[smtp] password is ignored without a username".to_string()),
+		(Some(_), _) if security == smtp::Security::Plain => {
+			report.warnings.push("[smtp] credentials are sent unencrypted with security = \"plain\"".to_string());
+		},
+		_ => {},
+	}
+
+	match &settings.from {
+		Some(from) => if let Err(e) = from.parse::<lettre::message::Mailbox>() {
+			report.errors.push(format!("[smtp] from: '{}' is not a valid address: {}", from, e));
+		},
+		None => report.errors.push("[smtp] no from address set".to_string()),
+	}
+	if let Some(digest) = &settings.digest {
+		if parse_duration(digest).is_none() {
+			report.errors.push(format!("[smtp] digest: invalid duration '{}', use e.g. 1h or 1d", digest));
+		}
+	}
+
+	let recipients = std::iter::once(("[smtp]".to_string(), &settings.to))
+		.chain(settings.destinations.iter().map(|(name, destination)| (format!("[smtp.destinations.{}]", name), &destination.to)));
+	for (location, addresses) in recipients {
+		for address in addresses {
+			if let Err(e) = address.parse::<lettre::message::Mailbox>() {
+				report.errors.push(format!("{} to: '{}' is not a valid address: {}", location, address, e));
+			}
+		}
+	}
+	for (name, destination) in settings.destinations.iter() {
+		if destination.to.is_empty() {
+			report.errors.push(format!("[smtp.destinations.{}] no recipients in to", name));
+		}
+	}
+
+	claim_names("smtp", smtp::destination_names(settings), destinations, report);
+}
+
+fn check_json_webhook(settings: &JsonWebhookSettings, destinations: &mut Vec<String>, report: &mut Report) {
+	let urls = settings.url.iter().map(|url| ("[webhook]".to_string(), url))
+		.chain(settings.destinations.iter().map(|(name, destination)| (format!("[webhook.destinations.{}]", name), &destination.url)));
+	for (location, url) in urls {
+		if !url.starts_with("http://") && !url.starts_with("https://") {
+			report.errors.push(format!("{} url: '{}' is not an http:// or https:// URL", location, url));
+		}
+	}
+
+	let headers = settings.headers.keys().map(|header| ("[webhook]".to_string(), header))
+		.chain(settings.destinations.iter().flat_map(|(name, destination)| destination.headers.keys().map(move |header| (format!("[webhook.destinations.{}]", name), header))))
+		.chain(settings.signature_header.iter().map(|header| ("[webhook] signature_header:".to_string(), header)));
+	for (location, header) in headers {
+		if reqwest::header::HeaderName::from_bytes(header.as_bytes()).is_err() {
+			report.errors.push(format!("{} '{}' is not a valid header name", location, header));
+		}
+	}
+
+	for (field, duration) in [("timeout", &settings.timeout), ("backoff", &settings.backoff)] {
+		if let Some(duration) = duration {
+			if parse_duration(duration).is_none() {
+				report.errors.push(format!("[webhook] {}: invalid duration '{}', use e.g. 10s", field, duration));
+			}
+		}
+	}
+	if settings.signature_header.is_some() && settings.hmac_secret.is_none() && settings.destinations.values().all(|destination| destination.hmac_secret.is_none()) {
+		report.warnings.push("[webhook] signature_header is set, but no hmac_secret to sign with".to_string());
+	}
+
+	claim_names("webhook", webhook::destination_names(settings), destinations, report);
+}
+
+fn check_settings(settings: &AppSettings) -> Report {
+	let mut report = Report::default();
+
+	if settings.telegram.api_key.is_none() && std::env::var("TELEGRAM_API_KEY").is_err() {
+		report.warnings.push("[telegram] no api_key set, and no TELEGRAM_API_KEY environment variable".to_string());
+	}
+
+	if let Some(api_url) = &settings.telegram.api_url {
+		if !api_url.starts_with("http://") && !api_url.starts_with("https://") {
+			report.errors.push(format!("[telegram] api_url: '{}' is not an http:// or https:// URL", api_url));
+		}
+	}
+
+	if let Some(window) = &settings.format.dedup_window {
+		if window != "0" && parse_duration(window).is_none() {
+			report.errors.push(format!("[format] dedup_window: invalid duration '{}', use e.g. 30s or 5m", window));
+		}
+	}
+
+	let journal = &settings.journal;
+	let sources = [!journal.files.is_empty(), journal.directory.is_some(), journal.namespace.is_some()];
+	if sources.iter().filter(|set| **set).count() > 1 {
+		report.warnings.push("[journal] more than one of files, directory and namespace set, files take precedence over directory, then namespace".to_string());
+	}
+	if !journal.files.is_empty() && journal.user_only.is_some() {
+		report.warnings.push("[journal] user_only is ignored when reading explicit files".to_string());
+	}
+	for path in journal.files.iter().chain(journal.directory.iter()) {
+		if !path.exists() {
+			report.warnings.push(format!("[journal] {} does not exist", path.display()));
+		}
+	}
+
+	if let Some(max_age) = &settings.spool.max_age {
+		if parse_duration(max_age).is_none() {
+			report.errors.push(format!("[spool] max_age: invalid duration '{}', use e.g. 12h or 3d", max_age));
+		}
+	}
+
+	if settings.telegram.destinations.contains_key(telegram::DEFAULT_DESTINATION) {
+		report.errors.push(format!("[telegram.destinations.{0}] the name '{0}' is reserved for telegram.chat_id", telegram::DEFAULT_DESTINATION));
+	}
+	let mut destinations: Vec<String> = Vec::new();
+	claim_names("telegram", telegram::destination_names(&settings.telegram), &mut destinations, &mut report);
+	check_webhooks("slack", &settings.slack, slack::destination_names(&settings.slack), &mut destinations, &mut report);
+	check_webhooks("discord", &settings.discord, discord::destination_names(&settings.discord), &mut destinations, &mut report);
+	check_matrix(&settings.matrix, &mut destinations, &mut report);
+	check_smtp(&settings.smtp, &mut destinations, &mut report);
+	check_json_webhook(&settings.webhook, &mut destinations, &mut report);
+
+	check_group_table("match", &settings.match_rules, &destinations, &mut report);
+	check_group_table("deny", &settings.deny_rules, &destinations, &mut report);
+	check_group_table("allow", &settings.allow_rules, &destinations, &mut report);
+	check_thresholds(settings, &destinations, &mut report);
+	// regexes, windows and key patterns are compiled exactly as at startup
+	report.errors.extend(filter::rule_errors(settings));
+	check_heartbeats(settings, &destinations, &mut report);
+	check_format(settings, &destinations, &mut report);
+
+	report
+}
+
+/// Validate a config file, printing every problem found. Returns the process exit code
+pub fn run(config_path: &str) -> i32 {
+	let settings = match parse_config(config_path) {
+		Ok(settings) => settings,
+		Err(e) => {
+			eprintln!("error: {}: {}", config_path, e);
+			return 1
+		}
+	};
+
+	let report = check_settings(&settings);
+	for warning in report.warnings.iter() {
+		eprintln!("warning: {}", warning);
+	}
+	for error in report.errors.iter() {
+		eprintln!("error: {}", error);
+	}
+
+	if report.errors.is_empty() {
+		println!("{}: OK ({} warnings)", config_path, report.warnings.len());
+		0
+	} else {
+		println!("{}: {} errors, {} warnings", config_path, report.errors.len(), report.warnings.len());
+		1
+	}
+}
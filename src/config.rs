@@ -2,17 +2,42 @@ use std::{collections::HashMap, fmt, path::PathBuf};
 use serde::{de::{self, Error, MapAccess, Visitor}, Deserialize, Deserializer};
 use serde_derive::Deserialize;
 
-use clap::{arg, command, value_parser};
+use clap::{arg, command, value_parser, Arg, ArgAction, Command};
+
+use crate::telegram::DEFAULT_DESTINATION;
+use crate::template::parse_priority;
 
 #[derive(Debug, Deserialize)]
 pub struct AppSettings {
 	pub telegram: TelegramSettings,
+	#[serde(default)]
+	pub slack: WebhookSettings,
+	#[serde(default)]
+	pub discord: WebhookSettings,
+	#[serde(default)]
+	pub matrix: MatrixSettings,
+	#[serde(default)]
+	pub smtp: SmtpSettings,
+	#[serde(default)]
+	pub webhook: JsonWebhookSettings,
+	#[serde(default)]
+	pub journal: JournalSettings,
+	#[serde(default)]
+	pub mute: MuteSettings,
+	#[serde(default)]
+	pub spool: SpoolSettings,
+	#[serde(default)]
+	pub format: FormatSettings,
 	#[serde(rename = "match", deserialize_with = "deserialize_rule_group")]
     pub match_rules: Option<HashMap<u32, Vec<Rule>>>,
 	#[serde(rename = "deny", deserialize_with = "deserialize_rule_group")]
     pub deny_rules: Option<HashMap<u32, Vec<Rule>>>,
 	#[serde(rename = "allow", deserialize_with = "deserialize_rule_group")]
     pub allow_rules: Option<HashMap<u32, Vec<Rule>>>,
+	#[serde(rename = "threshold", default, deserialize_with = "deserialize_priority_map")]
+	pub threshold_rules: HashMap<u32, ThresholdRule>,
+	#[serde(default)]
+	pub heartbeat: HeartbeatSettings,
 }
 
 impl Default for AppSettings {
@@ -20,12 +45,31 @@ impl Default for AppSettings {
 		AppSettings {
 			telegram: TelegramSettings {
 				chat_id: "".to_string(),
+				message_thread_id: None,
 				api_key: None,
+				api_url: None,
 				flush_seconds: None,
+				document_after: None,
+				notify_priority: None,
+				pin_priority: None,
+				destinations: HashMap::new(),
+				commands: None,
+				command_chats: Vec::new(),
 			},
+			slack: WebhookSettings::default(),
+			discord: WebhookSettings::default(),
+			matrix: MatrixSettings::default(),
+			smtp: SmtpSettings::default(),
+			webhook: JsonWebhookSettings::default(),
+			journal: JournalSettings::default(),
+			mute: MuteSettings::default(),
+			spool: SpoolSettings::default(),
+			format: FormatSettings::default(),
 			match_rules: Some(HashMap::new()),
 			deny_rules: Some(HashMap::new()),
 			allow_rules: Some(HashMap::new()),
+			threshold_rules: HashMap::new(),
+			heartbeat: HeartbeatSettings::default(),
 		}
 	}
 }
@@ -33,8 +77,224 @@ impl Default for AppSettings {
 #[derive(Debug, Deserialize)]
 pub struct TelegramSettings {
 	pub chat_id: String,
+	pub message_thread_id: Option<i64>,
 	pub api_key: Option<String>,
+	/// Bot API server to use, such as a self-hosted one. Defaults to https://api.telegram.org
+	pub api_url: Option<String>,
+	pub flush_seconds: Option<u16>,
+	/// Batches that would take more than this many messages are sent as one .log file with a summary
+	/// instead. 0 turns this off, defaults to 3
+	pub document_after: Option<usize>,
+	/// Batches whose highest priority is below this one (a level 0 to 7 or a name like err)
+	/// are sent silently. Every batch makes a sound if unset
+	#[serde(default, deserialize_with = "deserialize_priority")]
+	pub notify_priority: Option<u8>,
+	/// Pin messages whose highest priority is at or above this one
+	#[serde(default, deserialize_with = "deserialize_priority")]
+	pub pin_priority: Option<u8>,
+	/// Additional named chats that rule groups can route entries to
+	#[serde(default)]
+	pub destinations: HashMap<String, TelegramDestination>,
+	/// Answer bot commands, polling for them with getUpdates
+	pub commands: Option<bool>,
+	/// Chats allowed to send commands, in addition to the configured destinations
+	#[serde(default)]
+	pub command_chats: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TelegramDestination {
+	pub chat_id: String,
+	/// Forum topic to post into
+	pub message_thread_id: Option<i64>,
+	/// Overrides `telegram.notify_priority` for this chat
+	#[serde(default, deserialize_with = "deserialize_priority")]
+	pub notify_priority: Option<u8>,
+	/// Overrides `telegram.pin_priority` for this chat
+	#[serde(default, deserialize_with = "deserialize_priority")]
+	pub pin_priority: Option<u8>,
+}
+
+/// A chat service posted to through webhooks, like `[slack]` and `[discord]`
+#[derive(Debug, Deserialize, Default)]
+pub struct WebhookSettings {
+	/// Webhook that entries without a named destination are posted to
+	pub webhook_url: Option<String>,
+	pub flush_seconds: Option<u16>,
+	/// Additional named webhooks that rule groups can route entries to
+	#[serde(default)]
+	pub destinations: HashMap<String, WebhookDestination>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebhookDestination {
+	pub webhook_url: String,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct MatrixSettings {
+	/// Client-server API of the homeserver, e.g. https://matrix.example.org
+	pub homeserver: Option<String>,
+	/// Access token of the account posting, or the MATRIX_ACCESS_TOKEN environment variable
+	pub access_token: Option<String>,
+	/// Room that entries without a named destination are posted to, e.g. "!abc123:example.org"
+	pub room_id: Option<String>,
+	pub flush_seconds: Option<u16>,
+	/// Additional named rooms that rule groups can route entries to
+	#[serde(default)]
+	pub destinations: HashMap<String, MatrixDestination>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MatrixDestination {
+	pub room_id: String,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct SmtpSettings {
+	/// Mail server to submit through, e.g. smtp.example.org
+	pub host: Option<String>,
+	/// Defaults to 587, the submission port
+	pub port: Option<u16>,
+	/// "starttls" (the default) to require STARTTLS, or "plain" for an unencrypted connection such as a local relay
+	pub security: Option<String>,
+	pub username: Option<String>,
+	/// Password for `username`, or the SMTP_PASSWORD environment variable
+	pub password: Option<String>,
+	/// Sender address, e.g. "telelog <telelog@example.org>"
+	pub from: Option<String>,
+	/// Recipients of entries without a named destination
+	#[serde(default)]
+	pub to: Vec<String>,
+	/// How often entries below `urgent_priority` are sent as a digest, e.g. 1h or 1d. Defaults to 1h
+	pub digest: Option<String>,
+	/// Entries at or above this priority are emailed right away. Defaults to crit
+	#[serde(default, deserialize_with = "deserialize_priority")]
+	pub urgent_priority: Option<u8>,
+	/// Additional named recipient lists that rule groups can route entries to
+	#[serde(default)]
+	pub destinations: HashMap<String, SmtpDestination>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SmtpDestination {
+	pub to: Vec<String>,
+}
+
+/// Any HTTP endpoint, posted batches as JSON arrays of entries
+#[derive(Debug, Deserialize, Default)]
+pub struct JsonWebhookSettings {
+	/// Endpoint that entries without a named destination are posted to
+	pub url: Option<String>,
+	/// Extra headers sent with every request
+	#[serde(default)]
+	pub headers: HashMap<String, String>,
+	/// Sent as `Authorization: Bearer <token>`
+	pub bearer_token: Option<String>,
+	/// Key the body is signed with using HMAC-SHA256, the signature is sent as `sha256=<hex>`
+	pub hmac_secret: Option<String>,
+	/// Header the signature is sent in. Defaults to X-Telelog-Signature
+	pub signature_header: Option<String>,
+	/// How long a request may take, e.g. 10s. Defaults to 10s
+	pub timeout: Option<String>,
+	/// Times a failed request is repeated straight away before the batch is queued for later. Defaults to 3
+	pub retries: Option<u32>,
+	/// Wait before the first repeat, doubled after each one, e.g. 1s. Defaults to 1s
+	pub backoff: Option<String>,
 	pub flush_seconds: Option<u16>,
+	/// Additional named endpoints that rule groups can route entries to
+	#[serde(default)]
+	pub destinations: HashMap<String, JsonWebhookDestination>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct JsonWebhookDestination {
+	pub url: String,
+	/// Sent along with `webhook.headers`, replacing any with the same name
+	#[serde(default)]
+	pub headers: HashMap<String, String>,
+	/// Overrides `webhook.bearer_token` for this endpoint
+	pub bearer_token: Option<String>,
+	/// Overrides `webhook.hmac_secret` for this endpoint
+	pub hmac_secret: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct JournalSettings {
+	/// File the cursor of the last delivered entry is persisted to
+	pub state_file: Option<PathBuf>,
+	/// Maximum number of entries replayed when resuming from a saved cursor
+	pub max_catchup: Option<u64>,
+	/// Read the journal files in this directory, e.g. one filled by systemd-journal-remote
+	pub directory: Option<PathBuf>,
+	/// Read these journal files, takes precedence over `directory` and `namespace`
+	#[serde(default)]
+	pub files: Vec<PathBuf>,
+	/// Read this journal namespace instead of the default one
+	pub namespace: Option<String>,
+	/// Only read the current user's journal
+	pub user_only: Option<bool>,
+}
+
+/// Entries replayed when resuming from a saved cursor, unless `journal.max_catchup` says otherwise
+pub const DEFAULT_MAX_CATCHUP: u64 = 500;
+
+#[derive(Debug, Deserialize, Default)]
+pub struct MuteSettings {
+	/// File active mutes are persisted to
+	pub state_file: Option<PathBuf>,
+	/// Unix socket the `mute`, `unmute` and `mutes` subcommands talk to
+	pub control_socket: Option<PathBuf>,
+}
+
+pub const DEFAULT_CONTROL_SOCKET: &str = "/run/telelog/control.sock";
+
+#[derive(Debug, Deserialize, Default)]
+pub struct SpoolSettings {
+	/// Directory undelivered messages are kept in until they can be sent, one file per destination
+	pub directory: Option<PathBuf>,
+	/// Total size of spooled messages per destination before the oldest are dropped
+	pub max_bytes: Option<u64>,
+	/// How long a message may wait in the spool before it is dropped, e.g. 12h or 3d
+	pub max_age: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct FormatSettings {
+	/// Collapse similar entries from the same identifier logged within this long of each other
+	/// into one "repeated N times" line, e.g. 5m. Off when unset or "0"
+	pub dedup_window: Option<String>,
+	/// Template for each line, e.g. "{emoji}[{timestamp}] {_HOSTNAME} {identifier}: {message}".
+	/// Placeholders are journal fields or emoji (see `priority`), priority, priority_name,
+	/// timestamp, identifier, message and count
+	pub template: Option<String>,
+	/// Line added before each batch, with the placeholders count, first, last and hostname
+	pub header: Option<String>,
+	/// Line added after each batch, with the same placeholders as the header
+	pub footer: Option<String>,
+	/// strftime format of {timestamp}
+	pub timestamp_format: Option<String>,
+	/// local, UTC or a fixed offset like +02:00
+	pub timezone: Option<String>,
+	/// What {emoji} shows for each priority, keyed by level (0 to 7) or its name (err, warning...).
+	/// Any string works, e.g. "ERR", and "" shows nothing. Unset levels keep their default emoji
+	#[serde(default)]
+	pub priority: HashMap<String, String>,
+	/// Overrides for individual destinations, by name
+	#[serde(default)]
+	pub destinations: HashMap<String, FormatOverride>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct FormatOverride {
+	pub template: Option<String>,
+	pub header: Option<String>,
+	pub footer: Option<String>,
+	pub timestamp_format: Option<String>,
+	pub timezone: Option<String>,
+	/// Applied on top of the global `[format.priority]`
+	#[serde(default)]
+	pub priority: HashMap<String, String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -43,6 +303,50 @@ pub struct Rule {
     pub value: RuleValue,
     #[serde(rename = "rule", default = "Rule::default_rule")]
     pub logic: RuleLogic,
+    /// Named destinations entries selected by this rule's group are sent to
+    #[serde(default)]
+    pub destinations: Vec<String>,
+}
+
+/// Count entries matching `rules` per key, and send one alert instead of them
+/// once `count` arrive within `window`
+#[derive(Debug, Deserialize)]
+pub struct ThresholdRule {
+	pub rules: Vec<Rule>,
+	pub count: usize,
+	/// e.g. 30s, 10m
+	pub window: String,
+	/// Field entries are counted separately by, defaults to SYSLOG_IDENTIFIER
+	pub key: Option<String>,
+	/// Regex applied to the key field, counting by its first capture group instead of the whole value
+	pub key_pattern: Option<String>,
+	/// Priority of the alert, defaults to that of the entry crossing the threshold
+	#[serde(default, deserialize_with = "deserialize_priority")]
+	pub priority: Option<u8>,
+	#[serde(default)]
+	pub destinations: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct HeartbeatSettings {
+	/// File the last time each heartbeat was seen is persisted to
+	pub state_file: Option<PathBuf>,
+	/// Every other key is a named heartbeat
+	#[serde(flatten)]
+	pub rules: HashMap<String, HeartbeatRule>,
+}
+
+/// Alert when no entry matching `rules` has been logged for longer than `every`
+#[derive(Debug, Deserialize)]
+pub struct HeartbeatRule {
+	pub rules: Vec<Rule>,
+	/// e.g. 26h
+	pub every: String,
+	/// Priority of the alert, defaults to 3 (error)
+	#[serde(default, deserialize_with = "deserialize_priority")]
+	pub priority: Option<u8>,
+	#[serde(default)]
+	pub destinations: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -99,7 +403,7 @@ where
         {
             let mut map = HashMap::new();
             while let Some(key) = access.next_key::<String>()? {
-                let parsed_key = (&key).parse::<u32>().map_err(de::Error::custom)?;
+                let parsed_key = key.parse::<u32>().map_err(de::Error::custom)?;
                 let rules = match access.next_value()? {
                     Some(value) => match value {
                         toml::Value::Array(seq) => {
@@ -120,28 +424,146 @@ where
     deserializer.deserialize_option(RuleGroupVisitor)
 }
 
+/// Tables keyed by group priority, like `[threshold]`
+fn deserialize_priority_map<'de, D, T>(deserializer: D) -> Result<HashMap<u32, T>, D::Error>
+where
+	D: Deserializer<'de>,
+	T: Deserialize<'de>,
+{
+	HashMap::<String, T>::deserialize(deserializer)?
+		.into_iter()
+		.map(|(key, value)| key.parse::<u32>().map(|key| (key, value)).map_err(de::Error::custom))
+		.collect()
+}
 
-fn get_environment_variable(name: &str) -> Option<String> {
-	match std::env::var(name) {
-		Ok(value) => Some(value),
-		Err(_) => None,
+/// A priority written as its level, `3`, or its name, `"err"`
+fn deserialize_priority<'de, D>(deserializer: D) -> Result<Option<u8>, D::Error>
+where
+	D: Deserializer<'de>,
+{
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum Priority {
+		Level(u8),
+		Name(String),
+	}
+
+	match Option::<Priority>::deserialize(deserializer)? {
+		None => Ok(None),
+		Some(Priority::Level(level)) => parse_priority(&level.to_string()).map(Some).map_err(de::Error::custom),
+		Some(Priority::Name(name)) => parse_priority(&name).map(Some).map_err(de::Error::custom),
 	}
 }
 
+fn get_environment_variable(name: &str) -> Option<String> {
+	std::env::var(name).ok()
+}
+
+fn socket_arg() -> Arg {
+	arg!(
+			-s --socket <FILE> "Control socket of the running telelog, defaults to mute.control_socket"
+	)
+	.required(false)
+	.value_parser(value_parser!(PathBuf))
+}
+
+/// The control socket from the config file, without needing the rest of it to be valid
+pub fn control_socket(config_path: &str) -> PathBuf {
+	parse_config(config_path).ok()
+		.and_then(|settings| settings.mute.control_socket)
+		.unwrap_or_else(|| PathBuf::from(DEFAULT_CONTROL_SOCKET))
+}
+
 pub fn parse_cli_args() -> clap::ArgMatches {
-	return command!()
+	command!()
 		.arg(
 				arg!(
 						-c --config <FILE> "Sets a custom config file"
 				)
 				// We don't have syntax yet for optional options, so manually calling `required`
 				.required(false)
+				.global(true)
+				.value_parser(value_parser!(PathBuf)),
+		)
+		.arg(
+				arg!(
+						-w --watch "Reload the config file whenever it changes"
+				)
+				.action(ArgAction::SetTrue),
+		)
+		.arg(
+				arg!(
+						-D --directory <DIR> "Read the journal files in this directory"
+				)
+				.required(false)
+				.global(true)
 				.value_parser(value_parser!(PathBuf)),
 		)
+		.arg(
+				arg!(
+						--file <FILE> "Read this journal file, may be repeated"
+				)
+				.required(false)
+				.global(true)
+				.action(ArgAction::Append)
+				.value_parser(value_parser!(PathBuf)),
+		)
+		.arg(
+				arg!(
+						--namespace <NAMESPACE> "Read this journal namespace"
+				)
+				.required(false)
+				.global(true),
+		)
+		.arg(
+				arg!(
+						--user "Only read the current user's journal"
+				)
+				.global(true)
+				.action(ArgAction::SetTrue),
+		)
+		.subcommand(
+			Command::new("check")
+				.about("Validate the config file and its rules, exiting non-zero on errors"),
+		)
+		.subcommand(
+			Command::new("test")
+				.about("Explain which rules decide the outcome for journal entries")
+				.arg(arg!([FILE] "Entries to test, as JSON or journalctl export output. Reads stdin if omitted or '-'"))
+				.arg(
+					arg!(-f --format <FORMAT> "Format of the entries, detected if not given")
+					.required(false)
+					.value_parser(["json", "export"]),
+				)
+				.arg(
+					arg!(--cursor <CURSOR> "Test the journal entry at this cursor, may be repeated")
+					.required(false)
+					.action(ArgAction::Append),
+				),
+		)
+		.subcommand(
+			Command::new("mute")
+				.about("Temporarily ignore entries from an identifier, or with FIELD=value")
+				.arg(arg!(<RULE> "Identifier or FIELD=value, compared exactly, or [FIELD=]~regex matching the whole value"))
+				.arg(arg!(<DURATION> "How long to mute for, e.g. 30m, 2h, 1d"))
+				.arg(socket_arg()),
+		)
+		.subcommand(
+			Command::new("unmute")
+				.about("Remove a mute, or every mute if no rule is given")
+				.arg(arg!([RULE] "The rule as given to mute"))
+				.arg(socket_arg()),
+		)
+		.subcommand(
+			Command::new("mutes")
+				.about("List active mutes")
+				.arg(socket_arg()),
+		)
 		.get_matches()
 }
 
-pub fn read_config(filepath: &str) -> Result<AppSettings, toml::de::Error> {
+/// Parse the config file as written, without applying defaults or the environment
+pub fn parse_config(filepath: &str) -> Result<AppSettings, toml::de::Error> {
 
 	let config_str = match std::fs::read_to_string(filepath) {
         Ok(config) => config,
@@ -150,12 +572,16 @@ pub fn read_config(filepath: &str) -> Result<AppSettings, toml::de::Error> {
         }
     };
 
-	let config: Result<AppSettings, toml::de::Error> = toml::from_str(&config_str);
-	if config.is_err() {
-		return config;
-	}
+	toml::from_str(&config_str)
+}
 
-	let mut settings: AppSettings = config.unwrap();
+pub fn read_config(filepath: &str) -> Result<AppSettings, toml::de::Error> {
+
+	let mut settings: AppSettings = parse_config(filepath)?;
+
+	if settings.telegram.destinations.contains_key(DEFAULT_DESTINATION) {
+		return Err(toml::de::Error::custom(format!("[config] The destination name '{}' is reserved for telegram.chat_id", DEFAULT_DESTINATION)))
+	}
 
 	if settings.telegram.api_key.is_none() {
 		match get_environment_variable("TELEGRAM_API_KEY") {
@@ -168,9 +594,82 @@ pub fn read_config(filepath: &str) -> Result<AppSettings, toml::de::Error> {
 		}
 	}
 
+	if settings.matrix.homeserver.is_some() && settings.matrix.access_token.is_none() {
+		match get_environment_variable("MATRIX_ACCESS_TOKEN") {
+			Some(access_token) => {
+				settings.matrix.access_token = Some(access_token);
+			},
+			None => {
+				return Err(toml::de::Error::missing_field("[config] No access token set in file as matrix.access_token, and no MATRIX_ACCESS_TOKEN environment variable"))
+			}
+		}
+	}
+
+	if settings.smtp.host.is_some() && settings.smtp.username.is_some() && settings.smtp.password.is_none() {
+		match get_environment_variable("SMTP_PASSWORD") {
+			Some(password) => {
+				settings.smtp.password = Some(password);
+			},
+			None => {
+				return Err(toml::de::Error::missing_field("[config] No password set in file as smtp.password, and no SMTP_PASSWORD environment variable"))
+			}
+		}
+	}
+
 	if settings.telegram.flush_seconds.is_none() {
 		settings.telegram.flush_seconds = Some(5);
 	}
 
-	return Ok(settings);
-}
\ No newline at end of file
+	if settings.journal.state_file.is_none() {
+		settings.journal.state_file = Some(PathBuf::from("/var/lib/telelog/cursor"));
+	}
+
+	if settings.journal.max_catchup.is_none() {
+		settings.journal.max_catchup = Some(DEFAULT_MAX_CATCHUP);
+	}
+
+	if settings.mute.state_file.is_none() {
+		settings.mute.state_file = Some(PathBuf::from("/var/lib/telelog/mutes.json"));
+	}
+
+	if settings.mute.control_socket.is_none() {
+		settings.mute.control_socket = Some(PathBuf::from(DEFAULT_CONTROL_SOCKET));
+	}
+
+	if settings.spool.directory.is_none() {
+		settings.spool.directory = Some(PathBuf::from("/var/lib/telelog/spool"));
+	}
+
+	if settings.spool.max_bytes.is_none() {
+		settings.spool.max_bytes = Some(10 * 1024 * 1024);
+	}
+
+	if settings.spool.max_age.is_none() {
+		settings.spool.max_age = Some("3d".to_string());
+	}
+
+	if settings.heartbeat.state_file.is_none() {
+		settings.heartbeat.state_file = Some(PathBuf::from("/var/lib/telelog/heartbeats.json"));
+	}
+
+	Ok(settings)
+}
+
+/// Command line journal source options override the ones in the config file
+pub fn apply_journal_args(journal: &mut JournalSettings, args: &clap::ArgMatches) {
+	if let Some(directory) = args.get_one::<PathBuf>("directory") {
+		journal.directory = Some(directory.clone());
+	}
+
+	if let Some(files) = args.get_many::<PathBuf>("file") {
+		journal.files = files.cloned().collect();
+	}
+
+	if let Some(namespace) = args.get_one::<String>("namespace") {
+		journal.namespace = Some(namespace.clone());
+	}
+
+	if args.get_flag("user") {
+		journal.user_only = Some(true);
+	}
+}
@@ -0,0 +1,385 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+use tokio::time::sleep;
+
+use crate::config::{WebhookSettings};
+use crate::helpers::{collapse_repeats, colour_hex, is_permanent_failure, split_line, utf16_len, MAX_PAUSE};
+use crate::journal::LogEntry;
+use crate::sink::{Delivery, Sink};
+use crate::template::{Formats, LineFormat};
+
+/// Name of the destination built from the top level `discord.webhook_url`
+const DEFAULT_DESTINATION: &str = "discord";
+
+/// Longest embed description, the size of a plain message. Embeds may hold up to 4096,
+/// shorter ones leave room for several per message and stay readable
+const EMBED_LIMIT: usize = 2000;
+/// Most embeds Discord accepts in one message
+const EMBEDS_PER_MESSAGE: usize = 10;
+/// Most characters Discord accepts across all the embeds of one message
+const MESSAGE_LIMIT: usize = 6000;
+
+/// Priority of telelog's own notices, a warning
+const NOTICE_PRIORITY: u8 = 4;
+
+/// The fences around each embed's code block
+const CODE_OPEN: &str = "```\n";
+const CODE_CLOSE: &str = "```";
+
+/// One embed, coloured by the highest priority of the entries in it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Embed {
+	pub description: String,
+	pub color: u32,
+}
+
+impl Embed {
+	fn len(&self) -> usize {
+		utf16_len(&self.description)
+	}
+}
+
+/// Posts batches to a Discord webhook as embeds
+#[derive(Debug)]
+pub struct DiscordSink {
+	name: String,
+	webhook_url: String,
+	flush_seconds: u16,
+	dedup_window: Option<Duration>,
+	line_format: LineFormat,
+	http: reqwest::Client,
+	send_lock: Arc<AsyncMutex<()>>,
+}
+
+#[derive(Deserialize)]
+struct RateLimitResponse {
+	retry_after: Option<f64>,
+}
+
+/// Names rule groups can route to: the default webhook, if set, and every named destination
+pub fn destination_names(settings: &WebhookSettings) -> Vec<String> {
+	let mut names: Vec<String> = settings.webhook_url.iter().map(|_| DEFAULT_DESTINATION.to_string()).collect();
+	names.extend(settings.destinations.keys().cloned());
+	names
+}
+
+/// Build a sink for the default webhook and one for each named destination
+pub fn sinks(settings: &WebhookSettings, formats: &Formats) -> Vec<DiscordSink> {
+	let http = reqwest::Client::new();
+	let dedup_window = formats.dedup_window;
+
+	let mut webhooks: Vec<(String, String)> = settings.webhook_url.iter().map(|url| (DEFAULT_DESTINATION.to_string(), url.clone())).collect();
+	webhooks.extend(settings.destinations.iter().map(|(name, destination)| (name.clone(), destination.webhook_url.clone())));
+
+	webhooks.into_iter().map(|(name, webhook_url)| {
+		let line_format = formats.line_format(&name);
+
+		DiscordSink {
+			name,
+			webhook_url,
+			flush_seconds: settings.flush_seconds.unwrap_or(5),
+			dedup_window,
+			line_format,
+			http: http.clone(),
+			// Discord rate limits each webhook on its own
+			send_lock: Arc::new(AsyncMutex::new(())),
+		}
+	}).collect()
+}
+
+/// Break up backtick fences so an entry cannot close the code block it is in
+fn escape_markdown(text: &str) -> String {
+	text.replace("```", "`\u{200b}`\u{200b}`")
+}
+
+fn header_seconds(response: &reqwest::Response, name: &str) -> Option<f64> {
+	response.headers().get(name)
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.parse::<f64>().ok())
+}
+
+/// Seconds from a rate limit header or body as a pause, clamped to `0..=MAX_PAUSE`
+fn pause_duration(seconds: f64) -> Duration {
+	match Duration::try_from_secs_f64(seconds) {
+		Ok(pause) => pause.min(MAX_PAUSE),
+		Err(_) if seconds > 0.0 => MAX_PAUSE,
+		Err(_) => Duration::ZERO,
+	}
+}
+
+/// Keep the send lock for `seconds`, holding back every post to this webhook until then
+fn hold(guard: OwnedMutexGuard<()>, seconds: f64) {
+	let pause = pause_duration(seconds);
+	tokio::spawn(async move {
+		sleep(pause).await;
+		drop(guard);
+	});
+}
+
+impl DiscordSink {
+	async fn post(&self, embeds: &[Embed]) -> Result<reqwest::Response, reqwest::Error> {
+		let guard = self.send_lock.clone().lock_owned().await;
+		let payload = json!({
+			"embeds": embeds,
+			// log lines that happen to contain @everyone or a user ID should not ping anyone
+			"allowed_mentions": { "parse": [] },
+		});
+		let response = self.http.post(&self.webhook_url)
+			.header(reqwest::header::CONTENT_TYPE, "application/json")
+			.body(payload.to_string())
+			.send()
+			.await?;
+
+		// out of requests for this window, wait for it to reset before the next post
+		if header_seconds(&response, "x-ratelimit-remaining") == Some(0.0) {
+			if let Some(reset_after) = header_seconds(&response, "x-ratelimit-reset-after") {
+				hold(guard, reset_after);
+			}
+		}
+
+		Ok(response)
+	}
+
+	/// Split a batch into embeds of whole lines, then the embeds into messages
+	fn embeds(&self, entries: &[LogEntry]) -> Vec<Embed> {
+		let batch = collapse_repeats(entries, self.dedup_window);
+		let highest = entries.iter().map(|entry| entry.priority).min().unwrap_or(NOTICE_PRIORITY);
+
+		// header and footer lines have no priority of their own
+		let mut lines: Vec<(Option<u8>, String)> = Vec::new();
+		if let Some(header) = &self.line_format.header {
+			lines.push((None, self.line_format.batch_line(header, &batch)));
+		}
+		lines.extend(batch.iter().map(|repeated| (Some(repeated.entry.priority), self.line_format.line(repeated))));
+		if let Some(footer) = &self.line_format.footer {
+			lines.push((None, self.line_format.batch_line(footer, &batch)));
+		}
+
+		let room = EMBED_LIMIT - CODE_OPEN.len() - CODE_CLOSE.len();
+		let mut embeds: Vec<Embed> = Vec::new();
+		let mut current = String::new();
+		let mut current_length = 0;
+		let mut current_priority: Option<u8> = None;
+		let close = |text: &str, priority: Option<u8>| Embed {
+			description: format!("{}{}{}", CODE_OPEN, text, CODE_CLOSE),
+			color: colour_hex(priority.unwrap_or(highest)),
+		};
+
+		for (priority, line) in lines {
+			for piece in split_line(&line, room - 1) {
+				let length = utf16_len(&piece) + 1;
+				if current_length + length > room {
+					embeds.push(close(&current, current_priority));
+					current.clear();
+					current_length = 0;
+					current_priority = None;
+				}
+				current.push_str(&escape_markdown(&piece));
+				current.push('\n');
+				current_length += length;
+				current_priority = match (current_priority, priority) {
+					(Some(current), Some(priority)) => Some(current.min(priority)),
+					(current, priority) => current.or(priority),
+				};
+			}
+		}
+		if current_length > 0 {
+			embeds.push(close(&current, current_priority));
+		}
+		embeds
+	}
+}
+
+impl Sink for DiscordSink {
+	type Message = Vec<Embed>;
+
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn is_default(&self) -> bool {
+		self.name == DEFAULT_DESTINATION
+	}
+
+	fn flush_seconds(&self) -> u32 {
+		self.flush_seconds.into()
+	}
+
+	fn format(&self, entries: &[LogEntry]) -> Vec<Vec<Embed>> {
+		let mut messages: Vec<Vec<Embed>> = Vec::new();
+		for embed in self.embeds(entries) {
+			match messages.last_mut() {
+				Some(message) if message.len() < EMBEDS_PER_MESSAGE
+					&& message.iter().map(Embed::len).sum::<usize>() + embed.len() <= MESSAGE_LIMIT => message.push(embed),
+				_ => messages.push(vec![embed]),
+			}
+		}
+		messages
+	}
+
+	fn combine(&self, previous: &Vec<Embed>, next: &Vec<Embed>) -> Option<Vec<Embed>> {
+		let length: usize = previous.iter().chain(next.iter()).map(Embed::len).sum();
+		if previous.len() + next.len() > EMBEDS_PER_MESSAGE || length > MESSAGE_LIMIT {
+			return None
+		}
+		Some(previous.iter().chain(next.iter()).cloned().collect())
+	}
+
+	fn notice(&self, text: &str) -> Vec<Embed> {
+		vec![Embed {
+			description: format!("⚠️ {}", text),
+			color: colour_hex(NOTICE_PRIORITY),
+		}]
+	}
+
+	async fn deliver(&self, message: &Vec<Embed>) -> Delivery<Vec<Embed>> {
+		let response = match self.post(message).await {
+			Ok(response) => response,
+			Err(e) => {
+				eprintln!("[discord] Failed: {}", e);
+				return Delivery::Retry(message.clone())
+			}
+		};
+
+		if response.status().is_success() {
+			return Delivery::Sent
+		}
+
+		let status = response.status();
+		let retry_after_header = header_seconds(&response, "retry-after");
+		let text = response.text().await.unwrap_or_default();
+
+		match status.as_u16() {
+			429 => {
+				// the body has the precise wait, the header is rounded up to whole seconds
+				let retry_after = serde_json::from_str::<RateLimitResponse>(&text).ok()
+					.and_then(|body| body.retry_after)
+					.or(retry_after_header);
+				if let Some(retry_after) = retry_after {
+					eprintln!("[discord] API response 429: pausing messages for {} seconds", retry_after);
+					// take the lock before returning, so the next post is queued behind the pause
+					hold(self.send_lock.clone().lock_owned().await, retry_after);
+				}
+			},
+			_ if is_permanent_failure(status) => {
+				println!("[discord] API response {}: {:?}", status, text);
+				return Delivery::Drop(format!("{} {}", status, text))
+			},
+			_ => println!("[discord] API response {}: {:?}", status, text),
+		}
+
+		Delivery::Retry(message.clone())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+	use tokio::time::Instant;
+
+	use chrono::Local;
+
+	use super::*;
+	use crate::config::{SpoolSettings, WebhookDestination};
+	use crate::mock_http::MockServer;
+	use crate::sink;
+
+	fn settings(webhook_url: &str) -> WebhookSettings {
+		WebhookSettings {
+			webhook_url: Some(format!("{}/api/webhooks/1/abc", webhook_url)),
+			flush_seconds: Some(1),
+			destinations: [("alerts".to_string(), WebhookDestination { webhook_url: format!("{}/api/webhooks/2/def", webhook_url) })].into(),
+		}
+	}
+
+	fn entry(priority: u8, message: &str) -> LogEntry {
+		LogEntry::new(priority, Local::now(), "test".to_string(), message.to_string(), BTreeMap::new())
+	}
+
+	fn default_sink(settings: &WebhookSettings) -> DiscordSink {
+		sinks(settings, &Formats::default()).into_iter().find(|sink| sink.is_default()).unwrap()
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn posts_batches_as_coloured_embeds() {
+		let server = MockServer::start(Vec::new()).await;
+		let handle = sink::spawn(default_sink(&settings(&server.url)), &SpoolSettings::default());
+
+		handle.send(entry(6, "started @everyone")).await;
+		handle.send(entry(3, "failed ```")).await;
+		server.wait_for(1).await;
+
+		let requests = server.requests();
+		assert_eq!(requests.len(), 1);
+		assert_eq!(requests[0].path, "/api/webhooks/1/abc");
+
+		let body = requests[0].json();
+		assert_eq!(body["allowed_mentions"]["parse"], json!([]));
+		let embeds = body["embeds"].as_array().unwrap();
+		assert_eq!(embeds.len(), 1);
+		assert_eq!(embeds[0]["color"], 0xB22222);
+		let description = embeds[0]["description"].as_str().unwrap();
+		assert!(description.starts_with("```\n") && description.ends_with("\n```"));
+		assert!(description.contains("test: started @everyone\n"));
+		assert!(description.contains("test: failed `\u{200b}`\u{200b}`\n"));
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn splits_on_embed_and_message_limits() {
+		let discord = default_sink(&settings("http://localhost"));
+		let mut entries: Vec<LogEntry> = (0..200).map(|i| entry(6, &format!("{} {}", i, "x".repeat(300)))).collect();
+		entries[150].priority = 2;
+
+		let messages = discord.format(&entries);
+		assert!(messages.len() > 1);
+		for message in messages.iter() {
+			assert!(message.len() <= EMBEDS_PER_MESSAGE);
+			assert!(message.iter().map(Embed::len).sum::<usize>() <= MESSAGE_LIMIT);
+			for embed in message.iter() {
+				assert!(embed.len() <= EMBED_LIMIT);
+			}
+		}
+
+		let embeds: Vec<&Embed> = messages.iter().flatten().collect();
+		let critical: Vec<&&Embed> = embeds.iter().filter(|embed| embed.color == colour_hex(2)).collect();
+		assert_eq!(critical.len(), 1);
+		assert!(critical[0].description.contains("test: 150 "));
+		assert!(embeds.iter().all(|embed| embed.color == colour_hex(2) || embed.color == colour_hex(6)));
+		assert_eq!(embeds.iter().map(|embed| embed.description.matches(&"x".repeat(300)).count()).sum::<usize>(), 200);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn waits_for_rate_limit_reset() {
+		let server = MockServer::start(vec![
+			(204, vec![("X-RateLimit-Remaining", "0".to_string()), ("X-RateLimit-Reset-After", "1.5".to_string())], String::new()),
+			(429, vec![("Retry-After", "2".to_string())], r#"{"message":"You are being rate limited.","retry_after":1.2,"global":false}"#.to_string()),
+		]).await;
+		let discord = default_sink(&settings(&server.url));
+		let message = discord.notice("test");
+
+		let started = Instant::now();
+		assert!(matches!(discord.deliver(&message).await, Delivery::Sent));
+		assert!(matches!(discord.deliver(&message).await, Delivery::Retry(_)));
+		assert!(matches!(discord.deliver(&message).await, Delivery::Sent));
+
+		let requests = server.requests();
+		assert_eq!(requests.len(), 3);
+		let second = requests[1].received.duration_since(started);
+		let third = requests[2].received.duration_since(started);
+		assert!(second >= Duration::from_millis(1500));
+		assert!(third - second >= Duration::from_millis(1200) && third - second < Duration::from_secs(2));
+	}
+
+	#[test]
+	fn clamps_rate_limit_pauses() {
+		assert_eq!(pause_duration(1.5), Duration::from_millis(1500));
+		assert_eq!(pause_duration(-1.0), Duration::ZERO);
+		assert_eq!(pause_duration(f64::NAN), Duration::ZERO);
+		assert_eq!(pause_duration(1e30), MAX_PAUSE);
+		assert_eq!(pause_duration(f64::INFINITY), MAX_PAUSE);
+	}
+}
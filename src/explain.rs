@@ -0,0 +1,111 @@
+use std::io::Read;
+
+use systemd::journal as sysjournal;
+
+use crate::config::{apply_journal_args, parse_config};
+use crate::filter::{self, explain_log_entry, Route};
+use crate::journal::{self, read_cursor_entry};
+use crate::parser::{parse_export, parse_json, parse_message};
+
+fn read_input(file: Option<&String>) -> Result<Vec<u8>, String> {
+	let mut data = Vec::new();
+	match file.map(|file| file.as_str()) {
+		None | Some("-") => std::io::stdin().read_to_end(&mut data).map_err(|e| format!("Error reading stdin: {}", e))?,
+		Some(path) => {
+			data = std::fs::read(path).map_err(|e| format!("Error reading {}: {}", path, e))?;
+			data.len()
+		},
+	};
+	Ok(data)
+}
+
+fn read_records(args: &clap::ArgMatches) -> Result<Vec<sysjournal::JournalRecord>, String> {
+	if let Some(cursors) = args.get_many::<String>("cursor") {
+		return cursors.map(|cursor| read_cursor_entry(cursor)).collect()
+	}
+
+	let data = read_input(args.get_one::<String>("FILE"))?;
+	let format = match args.get_one::<String>("format") {
+		Some(format) => format.as_str(),
+		// journalctl's JSON output is one object per line, export output starts with a field name
+		None if data.iter().find(|b| !b.is_ascii_whitespace()).is_some_and(|b| *b == b'{' || *b == b'[') => "json",
+		None => "export",
+	};
+
+	match format {
+		"json" => parse_json(&String::from_utf8_lossy(&data)),
+		_ => parse_export(&data),
+	}
+}
+
+/// Explain how the rules in a config treat each given entry. Returns the process exit code
+pub fn run(config_path: &str, args: &clap::ArgMatches) -> i32 {
+	let mut settings = match parse_config(config_path) {
+		Ok(settings) => settings,
+		Err(e) => {
+			eprintln!("error: {}: {}", config_path, e);
+			return 1
+		}
+	};
+	if let Err(e) = filter::load_rules(&settings) {
+		eprintln!("error: {}: {}", config_path, e);
+		return 1
+	}
+	apply_journal_args(&mut settings.journal, args);
+	journal::set_source(&settings.journal);
+
+	let records = match read_records(args) {
+		Ok(records) => records,
+		Err(e) => {
+			eprintln!("error: {}", e);
+			return 1
+		}
+	};
+
+	if records.is_empty() {
+		eprintln!("error: no entries to test");
+		return 1
+	}
+
+	let mut malformed = false;
+	for (index, record) in records.into_iter().enumerate() {
+		let entry = match parse_message(record) {
+			Ok(entry) => entry,
+			Err(e) => {
+				eprintln!("error: entry #{}: {}", index + 1, e);
+				malformed = true;
+				continue
+			}
+		};
+		let explanation = explain_log_entry(&entry);
+
+		println!("#{} [{}] {}: {}", index + 1, entry.priority, entry.identifier, entry.message);
+
+		match explanation.selected_by {
+			None => println!("   match:   no [match] groups, every entry is read"),
+			Some(groups) if groups.is_empty() => println!("   match:   not selected by any [match] group, journald would not return it"),
+			Some(groups) => println!("   match:   selected by [match] group {}", groups.iter().map(|g| g.to_string()).collect::<Vec<_>>().join(", ")),
+		}
+
+		if let Some(priority) = explanation.counted_by {
+			println!("   result:  counted by [threshold] group {}, only sent as part of an alert", priority);
+			continue
+		}
+
+		match explanation.decided_by {
+			Some((action, priority)) => println!("   decided: priority {} [{}] group, action {:?}", priority, action.table(), action),
+			None => println!("   decided: no [deny]/[allow] group matched, allowed by default"),
+		}
+
+		match explanation.route {
+			Route::Drop => println!("   result:  dropped"),
+			Route::Default => println!("   result:  sent to the default destinations"),
+			Route::To(destinations) => println!("   result:  sent to {}", destinations.join(", ")),
+		}
+	}
+
+	match malformed {
+		true => 1,
+		false => 0,
+	}
+}
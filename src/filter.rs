@@ -1,20 +1,48 @@
-use std::borrow::Borrow;
-use std::sync::OnceLock;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-use crate::config::{AppSettings, RuleLogic, RuleValue};
+use chrono::{DateTime, Local};
+
+use crate::config::{AppSettings, Rule, RuleLogic, RuleValue};
+use crate::helpers::{format_duration, parse_duration, MAX_DURATION};
 use crate::journal::LogEntry;
 use systemd::Journal;
 use regex::Regex;
 
-static RULESET: OnceLock<RuleSet> = OnceLock::new();
+static RULESET: RwLock<Option<Arc<RuleSet>>> = RwLock::new(None);
+
+/// Timestamps of recent entries counted by `[threshold]` groups, by group priority and key
+type ThresholdCounts = BTreeMap<(u32, String), VecDeque<DateTime<Local>>>;
+static THRESHOLD_COUNTS: Mutex<ThresholdCounts> = Mutex::new(BTreeMap::new());
 
 
-#[derive(Debug, PartialEq)]
-enum RuleAction {
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RuleAction {
 	Allow,
 	Deny,
 }
 
+impl RuleAction {
+	pub fn table(&self) -> &'static str {
+		match self {
+			RuleAction::Allow => "allow",
+			RuleAction::Deny => "deny",
+		}
+	}
+}
+
+/// Where an entry should be delivered to
+#[derive(Debug, PartialEq, Clone)]
+pub enum Route {
+	/// The entry is filtered out
+	Drop,
+	/// The entry goes to the default destinations
+	Default,
+	/// The entry goes to these named destinations
+	To(Vec<String>),
+}
+
 #[derive(Debug, Clone)]
 struct RuleField {
 	field: String,
@@ -27,17 +55,88 @@ struct RuleGroup {
 	priority: u32,
 	action: RuleAction,
 	rules: Vec<RuleField>,
+	destinations: Vec<String>,
+}
+
+/// A `[match]` group, evaluated in process to route the entries it selects
+#[derive(Debug)]
+struct MatchGroup {
+	priority: u32,
+	rules: Vec<(String, Vec<String>, RuleLogic)>,
+	destinations: Vec<String>,
+}
+
+impl MatchGroup {
+	fn is_match(&self, entry: &LogEntry) -> bool {
+		self.rules.iter().all(|(field, values, logic)| {
+			let log_field = match entry.get_field(field) {
+				Ok(v) => v,
+				Err(_) => return false,
+			};
+
+			match logic {
+				RuleLogic::Any => values.contains(&log_field),
+				RuleLogic::All => values.iter().all(|value| *value == log_field),
+			}
+		})
+	}
+}
+
+/// A `[threshold]` group, counting the entries it matches per key
+#[derive(Debug)]
+struct ThresholdGroup {
+	priority: u32,
+	rules: Vec<RuleField>,
+	count: usize,
+	window: Duration,
+	key: String,
+	key_pattern: Option<Regex>,
+	alert_priority: Option<u8>,
+	destinations: Vec<String>,
+}
+
+impl ThresholdGroup {
+	/// The value entries are counted by, the first capture group of `key_pattern` if there is one
+	fn key_of(&self, entry: &LogEntry) -> String {
+		let value = entry.get_field(&self.key).unwrap_or_default();
+		match &self.key_pattern {
+			Some(re) => re.captures(&value)
+				.and_then(|captures| captures.get(1).or_else(|| captures.get(0)))
+				.map(|key| key.as_str().to_string())
+				.unwrap_or_default(),
+			None => value,
+		}
+	}
+
+	/// The window for comparing entry timestamps, capped at the longest duration a config can give
+	fn chrono_window(&self) -> chrono::Duration {
+		chrono::Duration::from_std(self.window.min(MAX_DURATION)).expect("a year fits in a chrono::Duration")
+	}
+}
+
+/// What the `[threshold]` groups did with an entry
+pub enum Threshold {
+	/// No threshold group matched, route the entry as usual
+	Pass,
+	/// The entry was counted and is not delivered itself
+	Counted,
+	/// The entry took its key over the threshold, deliver this alert in its place
+	Alert(LogEntry, Route),
 }
 
 #[derive(Debug)]
 struct RuleSet {
 	filters: Vec<RuleGroup>,
+	matches: Vec<MatchGroup>,
+	thresholds: Vec<ThresholdGroup>,
 }
 
 impl RuleSet {
 	pub fn new() -> Self {
 		RuleSet {
 			filters: Vec::new(),
+			matches: Vec::new(),
+			thresholds: Vec::new(),
 		}
 	}
 
@@ -84,8 +183,24 @@ impl RuleSet {
 	// }
 }
 
-pub fn init(settings: &AppSettings, journal: &mut Journal) {
-	let mut partial_rule_set = RuleSet::new();
+/// Collect the destinations named by any rule in a group, keeping the first occurrence of each
+fn group_destinations(rules: &[Rule]) -> Vec<String> {
+	let mut destinations: Vec<String> = Vec::new();
+	for destination in rules.iter().flat_map(|rule| rule.destinations.iter()) {
+		if !destinations.contains(destination) {
+			destinations.push(destination.clone());
+		}
+	}
+	destinations
+}
+
+/// Add the `[match]` groups to the journal as journald matches. Returns any errors, which are also printed
+fn add_journal_matches(settings: &AppSettings, journal: &mut Journal) -> Vec<String> {
+	let mut errors: Vec<String> = Vec::new();
+	let mut report = |error: String| {
+		println!("[filter init] {}", error);
+		errors.push(error);
+	};
 
 	if let Some(rule_groups) = &settings.match_rules {
 		let mut group_iter = rule_groups.iter().peekable();
@@ -98,7 +213,7 @@ pub fn init(settings: &AppSettings, journal: &mut Journal) {
 					RuleValue::Single(value) => {
 						match journal.match_add(journald_field, value.as_bytes()) {
 							Ok(_) => {},
-							Err(e) => println!("[filter init] Error adding {} filter: {}", journald_field, e)
+							Err(e) => report(format!("Error adding {} filter: {}", journald_field, e))
 						}
 					},
 					RuleValue::Multiple(values) => {
@@ -107,19 +222,19 @@ pub fn init(settings: &AppSettings, journal: &mut Journal) {
 						while let Some(value) = values_iter.next() {
 							match journal.match_add(journald_field, value.as_bytes()) {
 								Ok(_) => {},
-								Err(e) => println!("[filter init] Error adding {} filter: {}", journald_field, e)
+								Err(e) => report(format!("Error adding {} filter: {}", journald_field, e))
 							}
 
 							if values_iter.peek().is_some() {
 								if rule.logic == RuleLogic::All {
 									match journal.match_and() {
 										Ok(_) => {},
-										Err(e) => println!("[filter init] Error adding match AND filter: {}", e)
+										Err(e) => report(format!("Error adding match AND filter: {}", e))
 									}
 								} else {
 									match journal.match_or() {
 										Ok(_) => {},
-										Err(e) => println!("[filter init] Error adding match OR filter: {}", e)
+										Err(e) => report(format!("Error adding match OR filter: {}", e))
 									}
 								}
 							}
@@ -131,7 +246,7 @@ pub fn init(settings: &AppSettings, journal: &mut Journal) {
 				if rules_iter.peek().is_some() {
 					match journal.match_and() {
 						Ok(_) => {},
-						Err(e) => println!("[filter init] Error adding AND filter: {}", e)
+						Err(e) => report(format!("Error adding AND filter: {}", e))
 					}
 				}
 			}
@@ -140,142 +255,453 @@ pub fn init(settings: &AppSettings, journal: &mut Journal) {
 			if group_iter.peek().is_some() {
 				match journal.match_or() {
 					Ok(_) => {},
-					Err(e) => println!("[filter init] Error adding OR filter: {}", e)
+					Err(e) => report(format!("Error adding OR filter: {}", e))
 				}
 			}
 		}
 	}
 
-	if let Some(rule_groups) = &settings.deny_rules {
-		for (priority,rules) in rule_groups.iter() {
-			
-			let new_rule_list: Vec<RuleField> = rules.iter().filter_map(|rule| {
-				match &rule.value {
-					RuleValue::Single(value) => {
-						let re = Regex::new(value);
-						if re.is_err() {
-							println!("[filter init] Error compiling regex for '{}': {}", rule.field, re.err().unwrap());
-							return None;
-						}
-						let re = re.unwrap();
+	errors
+}
 
-						Some(RuleField {
-							field: rule.field.clone(),
-							re: vec![re],
-							logic: RuleLogic::Any, // single value rules dont really matter what the logical op is
-						})
-					},
-					RuleValue::Multiple(values) => {
+/// The last line of a regex error, the full message spans several lines pointing at the offending character
+fn regex_error_summary(error: &regex::Error) -> String {
+	let error = error.to_string();
+	error.lines().last().unwrap_or_default().trim_start_matches("error: ").to_string()
+}
 
-						let mut compiled_list = Vec::<Regex>::new();
-						for value in values.iter() {
-							let re = Regex::new(value);
-							if re.is_err() {
-								println!("[filter init] Error compiling regex for '{}': {}", rule.field, re.err().unwrap());
-								continue;
-							}
-							let re = re.unwrap();
-							compiled_list.push(re);
-						}
-						Some(RuleField {
-							field: rule.field.clone(),
-							re: compiled_list,
-							logic: rule.logic.clone(),
-						})
-					},
-				}
-			}).collect();
+/// Compile the regexes of the rules in a group. Values that fail to compile are skipped and
+/// reported in `errors`, prefixed with `location`
+fn compile_rules(location: &str, rules: &[Rule], errors: &mut Vec<String>) -> Vec<RuleField> {
+	rules.iter().enumerate().map(|(index, rule)| {
+		let values = match &rule.value {
+			RuleValue::Single(value) => std::slice::from_ref(value),
+			RuleValue::Multiple(values) => values.as_slice(),
+		};
+
+		let mut compiled_list = Vec::<Regex>::new();
+		for (value_index, value) in values.iter().enumerate() {
+			match Regex::new(value) {
+				Ok(re) => compiled_list.push(re),
+				Err(e) => errors.push(format!("{}, rule {}, field {}, value {}: invalid regex '{}': {}",
+					location, index + 1, rule.field, value_index + 1, value, regex_error_summary(&e))),
+			}
+		}
+
+		RuleField {
+			field: rule.field.clone(),
+			re: compiled_list,
+			// single value rules dont really matter what the logical op is
+			logic: match &rule.value {
+				RuleValue::Single(_) => RuleLogic::Any,
+				RuleValue::Multiple(_) => rule.logic,
+			},
+		}
+	}).collect()
+}
+
+/// Groups of a rule table in priority order, so errors are reported in a stable order
+fn sorted<T>(groups: &HashMap<u32, T>) -> Vec<(&u32, &T)> {
+	let mut groups: Vec<(&u32, &T)> = groups.iter().collect();
+	groups.sort_by_key(|(priority, _)| **priority);
+	groups
+}
+
+/// Build the rule set from the config. Returns every regex, window or key_pattern that failed,
+/// with where it is in the config
+fn compile_rule_set(settings: &AppSettings) -> (RuleSet, Vec<String>) {
+	let mut partial_rule_set = RuleSet::new();
+	let mut errors: Vec<String> = Vec::new();
 
+	if let Some(rule_groups) = &settings.match_rules {
+		for (priority,rules) in rule_groups.iter() {
+			let destinations = group_destinations(rules);
+			partial_rule_set.matches.push(MatchGroup {
+				priority: *priority,
+				rules: rules.iter().map(|rule| {
+					let values = match &rule.value {
+						RuleValue::Single(value) => vec![value.clone()],
+						RuleValue::Multiple(values) => values.clone(),
+					};
+					(rule.field.clone(), values, rule.logic)
+				}).collect(),
+				destinations,
+			});
+		}
+	}
+	partial_rule_set.matches.sort_by_key(|group| group.priority);
+
+	if let Some(rule_groups) = &settings.deny_rules {
+		for (priority,rules) in sorted(rule_groups) {
 			let new_rule_group = RuleGroup {
 				priority: *priority,
 				action: RuleAction::Deny,
-				rules: new_rule_list,
+				rules: compile_rules(&format!("[deny] group {}", priority), rules, &mut errors),
+				destinations: Vec::new(),
 			};
 			partial_rule_set.add(new_rule_group);
 		}
 	}
 
 	if let Some(rule_groups) = &settings.allow_rules {
-		for (priority,rules) in rule_groups.iter() {
-			
-			let new_rule_list: Vec<RuleField> = rules.iter().filter_map(|rule| {
-				match &rule.value {
-					RuleValue::Single(value) => {
-						let re = Regex::new(value);
-						if re.is_err() {
-							println!("[filter init] Error compiling regex for '{}': {}", rule.field, re.err().unwrap());
-							return None;
-						}
-						let re = re.unwrap();
-
-						Some(RuleField {
-							field: rule.field.clone(),
-							re: vec![re],
-							logic: RuleLogic::Any, // single value rules dont really matter what the logical op is
-						})
-					},
-					RuleValue::Multiple(values) => {
-
-						let mut compiled_list = Vec::<Regex>::new();
-						for value in values.iter() {
-							let re = Regex::new(value);
-							if re.is_err() {
-								println!("[filter init] Error compiling regex for '{}': {}", rule.field, re.err().unwrap());
-								continue;
-							}
-							let re = re.unwrap();
-							compiled_list.push(re);
-						}
-						Some(RuleField {
-							field: rule.field.clone(),
-							re: compiled_list,
-							logic: rule.logic.clone(),
-						})
-					},
-				}
-			}).collect();
-
+		for (priority,rules) in sorted(rule_groups) {
 			let new_rule_group = RuleGroup {
 				priority: *priority,
 				action: RuleAction::Allow,
-				rules: new_rule_list,
+				rules: compile_rules(&format!("[allow] group {}", priority), rules, &mut errors),
+				destinations: group_destinations(rules),
 			};
 			partial_rule_set.add(new_rule_group);
 		}
 	}
 
-	RULESET.set(partial_rule_set).expect("Initialisation occurs once");
+	for (priority, threshold) in sorted(&settings.threshold_rules) {
+		let location = format!("[threshold] group {}", priority);
+		let rules = compile_rules(&location, &threshold.rules, &mut errors);
+
+		let window = match parse_duration(&threshold.window) {
+			Some(window) => window,
+			None => {
+				errors.push(format!("{}: invalid window '{}', use e.g. 30s or 10m", location, threshold.window));
+				continue
+			}
+		};
+		let key_pattern = match threshold.key_pattern.as_deref().map(Regex::new).transpose() {
+			Ok(key_pattern) => key_pattern,
+			Err(e) => {
+				errors.push(format!("{}: invalid key_pattern '{}': {}", location, threshold.key_pattern.as_deref().unwrap_or_default(), regex_error_summary(&e)));
+				continue
+			}
+		};
+
+		partial_rule_set.thresholds.push(ThresholdGroup {
+			priority: *priority,
+			rules,
+			count: threshold.count.max(1),
+			window,
+			key: threshold.key.clone().unwrap_or_else(|| "SYSLOG_IDENTIFIER".to_string()),
+			key_pattern,
+			alert_priority: threshold.priority,
+			destinations: threshold.destinations.clone(),
+		});
+	}
+	partial_rule_set.thresholds.sort_by_key(|group| group.priority);
+
+	(partial_rule_set, errors)
 }
 
-/// Returns true if a log should be filtered (ignored), returns false if it should be processed
-pub fn filter_log_entry(entry: &LogEntry) -> bool {
-	let ruleset: &Vec<RuleGroup> = RULESET.get().unwrap().filters.borrow();
-	
-	for rule_group in ruleset.iter() {
-		let group_is_match = rule_group.rules.iter().all(|rule| { // when multiple rules are specified in a group, they are always ANDed together
-			let log_field = match entry.get_field(&rule.field) {
-				Ok(v) => v,
-				Err(e) => {
-					println!("[filter_log_entry] Error getting field {}: {}", &rule.field, e);
-					return false;
-				},
-			};
+/// Every error compiling the rules would fail with, as `telelog check` reports them
+pub fn rule_errors(settings: &AppSettings) -> Vec<String> {
+	compile_rule_set(settings).1
+}
 
-			let matched = match rule.logic {
-				RuleLogic::Any => rule.re.iter().any(|re| re.is_match(&log_field)),
-				RuleLogic::All => rule.re.iter().all(|re| re.is_match(&log_field)),
-			};
+/// Compile the rules and add the `[match]` groups to the journal. Fails on the first problem found,
+/// with every error joined
+pub fn init(settings: &AppSettings, journal: &mut Journal) -> Result<(), String> {
+	load_rules(settings)?;
+	let errors = add_journal_matches(settings, journal);
+	if !errors.is_empty() {
+		return Err(errors.join("; "));
+	}
+	Ok(())
+}
 
-			return matched
-		});
+/// Compile and install the rule set without touching a journal
+pub fn load_rules(settings: &AppSettings) -> Result<(), String> {
+	let (rule_set, errors) = compile_rule_set(settings);
+	if !errors.is_empty() {
+		return Err(errors.join("; "));
+	}
+	*RULESET.write().unwrap() = Some(Arc::new(rule_set));
+	Ok(())
+}
+
+/// Replace the rule set and journal matches with those from a new config.
+/// Nothing changes if any rule fails to compile or any match is rejected by the journal
+pub fn reload(settings: &AppSettings, previous: &AppSettings, journal: &mut Journal) -> Result<(), String> {
+	let (rule_set, errors) = compile_rule_set(settings);
+	if !errors.is_empty() {
+		return Err(errors.join("; "));
+	}
+
+	journal.match_flush().map_err(|e| format!("Error clearing journal matches: {}", e))?;
+	let errors = add_journal_matches(settings, journal);
+	if !errors.is_empty() {
+		// put the old matches back so we keep following the same entries
+		let _ = journal.match_flush();
+		add_journal_matches(previous, journal);
+		return Err(errors.join("; "));
+	}
 
-		if group_is_match {
-			return match rule_group.action {
-				RuleAction::Allow => false,
-				RuleAction::Deny => true,
+	*RULESET.write().unwrap() = Some(Arc::new(rule_set));
+	Ok(())
+}
+
+fn current() -> Arc<RuleSet> {
+	RULESET.read().unwrap().clone().expect("Rule set is initialised before use")
+}
+
+/// Every destination the config routes entries to: named by `[match]` and `[allow]` rules,
+/// thresholds and heartbeats. Destinations on `[deny]` rules are ignored, so they are left out
+pub fn routed_destinations(settings: &AppSettings) -> Vec<String> {
+	let groups = [&settings.match_rules, &settings.allow_rules].into_iter()
+		.flat_map(|groups| groups.iter().flat_map(|groups| groups.values()));
+	let named = groups.flat_map(|rules| rules.iter().flat_map(|rule| rule.destinations.iter()))
+		.chain(settings.threshold_rules.values().flat_map(|threshold| threshold.destinations.iter()))
+		.chain(settings.heartbeat.rules.values().flat_map(|heartbeat| heartbeat.destinations.iter()));
+
+	let mut destinations: Vec<String> = Vec::new();
+	for destination in named {
+		if !destinations.contains(destination) {
+			destinations.push(destination.clone());
+		}
+	}
+	destinations
+}
+
+/// Decide where a log entry goes. The first `[deny]`/`[allow]` group to match decides whether it is
+/// dropped; an allow group naming destinations routes it there, otherwise it goes to the destinations
+/// of the `[match]` groups that select it, or to the default destinations if none name any
+pub fn route_log_entry(entry: &LogEntry) -> Route {
+	let ruleset = current();
+	match deciding_group(&ruleset, entry) {
+		Some(rule_group) if rule_group.action == RuleAction::Deny => return Route::Drop,
+		Some(rule_group) if !rule_group.destinations.is_empty() => return Route::To(rule_group.destinations.clone()),
+		_ => {}, // if no rules match, allow the log through by default
+	}
+
+	let mut destinations: Vec<String> = Vec::new();
+	for group in ruleset.matches.iter().filter(|group| group.is_match(entry)) {
+		for destination in group.destinations.iter() {
+			if !destinations.contains(destination) {
+				destinations.push(destination.clone());
 			}
 		}
 	}
-	
-	false // if no rules match, allow the log through by default
-}
\ No newline at end of file
+
+	if destinations.is_empty() {
+		Route::Default
+	} else {
+		Route::To(destinations)
+	}
+}
+
+/// Returns true if every rule of a group matches the entry
+fn rules_match(rules: &[RuleField], entry: &LogEntry) -> bool {
+	rules.iter().all(|rule| { // when multiple rules are specified in a group, they are always ANDed together
+		let log_field = match entry.get_field(&rule.field) {
+			Ok(v) => v,
+			Err(e) => {
+				println!("[filter_log_entry] Error getting field {}: {}", &rule.field, e);
+				return false;
+			},
+		};
+
+		match rule.logic {
+			RuleLogic::Any => rule.re.iter().any(|re| re.is_match(&log_field)),
+			RuleLogic::All => rule.re.iter().all(|re| re.is_match(&log_field)),
+		}
+	})
+}
+
+/// The compiled rules of a group kept outside the rule set, such as a heartbeat's
+#[derive(Debug)]
+pub struct Rules(Vec<RuleField>);
+
+impl Rules {
+	/// Values that fail to compile are skipped and reported in `errors`, prefixed with `location`
+	pub fn compile(location: &str, rules: &[Rule], errors: &mut Vec<String>) -> Self {
+		Rules(compile_rules(location, rules, errors))
+	}
+
+	pub fn is_match(&self, entry: &LogEntry) -> bool {
+		rules_match(&self.0, entry)
+	}
+}
+
+/// Returns the first rule group that matches the entry
+fn deciding_group<'a>(ruleset: &'a RuleSet, entry: &LogEntry) -> Option<&'a RuleGroup> {
+	ruleset.filters.iter().find(|rule_group| rules_match(&rule_group.rules, entry))
+}
+
+/// Count the entry against the first `[threshold]` group that matches it. Entries a group counts
+/// are not delivered; once `count` of them arrive within the window for one key, a single alert
+/// is returned in place of the last one and counting for that key starts over.
+/// Entries a `[deny]` group decides to drop are never counted, they pass on to be dropped
+pub fn count_log_entry(entry: &LogEntry) -> Threshold {
+	count_entry(&current(), &mut THRESHOLD_COUNTS.lock().unwrap(), entry)
+}
+
+fn count_entry(ruleset: &RuleSet, counts: &mut ThresholdCounts, entry: &LogEntry) -> Threshold {
+	if deciding_group(ruleset, entry).is_some_and(|rule_group| rule_group.action == RuleAction::Deny) {
+		return Threshold::Pass
+	}
+	let group = match ruleset.thresholds.iter().find(|group| rules_match(&group.rules, entry)) {
+		Some(group) => group,
+		None => return Threshold::Pass,
+	};
+
+	let key = group.key_of(entry);
+	let window = group.chrono_window();
+
+	let times = counts.entry((group.priority, key.clone())).or_default();
+	times.push_back(entry.timestamp);
+	while times.front().is_some_and(|time| entry.timestamp - *time > window) {
+		times.pop_front();
+	}
+
+	if times.len() < group.count {
+		// forget keys that have gone quiet, so one-off keys don't pile up
+		if counts.len() > 1000 {
+			counts.retain(|(priority, _), times| {
+				let window = ruleset.thresholds.iter()
+					.find(|group| group.priority == *priority)
+					.map(|group| group.chrono_window())
+					.unwrap_or_default();
+				times.back().is_some_and(|last| entry.timestamp - *last <= window)
+			});
+		}
+		return Threshold::Counted
+	}
+	counts.remove(&(group.priority, key.clone()));
+
+	let mut alert = entry.clone();
+	alert.priority = group.alert_priority.unwrap_or(entry.priority);
+	alert.message = match key.is_empty() {
+		true => format!("{} matching entries within {} [threshold {}], last: {}", group.count, format_duration(group.window), group.priority, entry.message),
+		false => format!("{} matching entries for {} within {} [threshold {}], last: {}", group.count, key, format_duration(group.window), group.priority, entry.message),
+	};
+
+	let route = match group.destinations.is_empty() {
+		true => Route::Default,
+		false => Route::To(group.destinations.clone()),
+	};
+	Threshold::Alert(alert, route)
+}
+
+/// Why an entry would be routed the way it is, reported by the `test` subcommand
+pub struct Explanation {
+	/// Priorities of the `[match]` groups that select the entry, `None` if there are no `[match]` groups
+	pub selected_by: Option<Vec<u32>>,
+	/// Priority of the `[threshold]` group that counts the entry instead of it being delivered
+	pub counted_by: Option<u32>,
+	/// Action and priority of the `[deny]`/`[allow]` group that decided the outcome
+	pub decided_by: Option<(RuleAction, u32)>,
+	pub route: Route,
+}
+
+pub fn explain_log_entry(entry: &LogEntry) -> Explanation {
+	let ruleset = current();
+
+	let selected_by = match ruleset.matches.is_empty() {
+		true => None,
+		false => Some(ruleset.matches.iter()
+			.filter(|group| group.is_match(entry))
+			.map(|group| group.priority)
+			.collect()),
+	};
+
+	let decided_by = deciding_group(&ruleset, entry)
+		.map(|rule_group| (rule_group.action, rule_group.priority));
+
+	// denied entries are dropped before any threshold counts them
+	let counted_by = match decided_by {
+		Some((RuleAction::Deny, _)) => None,
+		_ => ruleset.thresholds.iter()
+			.find(|group| rules_match(&group.rules, entry))
+			.map(|group| group.priority),
+	};
+
+	Explanation {
+		selected_by,
+		counted_by,
+		decided_by,
+		route: route_log_entry(entry),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::TimeZone;
+
+	use super::*;
+
+	fn rule_set(config: &str) -> RuleSet {
+		let settings: AppSettings = toml::from_str(&format!("[telegram]\nchat_id = \"1\"\n[match]\n[allow]\n{}", config)).unwrap();
+		let (rule_set, errors) = compile_rule_set(&settings);
+		assert!(errors.is_empty(), "{:?}", errors);
+		rule_set
+	}
+
+	const SSH_FAILURES: &str = r#"
+[deny]
+1 = [{ field = "MESSAGE", value = "^Connection closed by authenticating user test" }]
+[threshold.2]
+window = "1m"
+count = 3
+rules = [{ field = "MESSAGE", value = "^Failed password" }]
+priority = 2
+destinations = ["security"]
+"#;
+
+	fn entry(seconds: i64, message: &str) -> LogEntry {
+		let timestamp = Local.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap() + chrono::Duration::seconds(seconds);
+		LogEntry::new(5, timestamp, "sshd".to_string(), message.to_string(), BTreeMap::new())
+	}
+
+	#[test]
+	fn alerts_once_the_threshold_is_crossed() {
+		let rule_set = rule_set(SSH_FAILURES);
+		let mut counts = ThresholdCounts::new();
+
+		assert!(matches!(count_entry(&rule_set, &mut counts, &entry(0, "Accepted publickey")), Threshold::Pass));
+		assert!(matches!(count_entry(&rule_set, &mut counts, &entry(0, "Failed password for root")), Threshold::Counted));
+		assert!(matches!(count_entry(&rule_set, &mut counts, &entry(10, "Failed password for root")), Threshold::Counted));
+		match count_entry(&rule_set, &mut counts, &entry(20, "Failed password for admin")) {
+			Threshold::Alert(alert, route) => {
+				assert_eq!(alert.priority, 2);
+				assert_eq!(alert.message, "3 matching entries for sshd within 1m 0s [threshold 2], last: Failed password for admin");
+				assert_eq!(route, Route::To(vec!["security".to_string()]));
+			},
+			_ => panic!("third entry within the window did not alert"),
+		}
+		// counting starts over after an alert
+		assert!(matches!(count_entry(&rule_set, &mut counts, &entry(30, "Failed password for root")), Threshold::Counted));
+	}
+
+	#[test]
+	fn alert_priorities_take_names_and_levels() {
+		let config = SSH_FAILURES.replace("priority = 2", "priority = \"crit\"");
+		assert_eq!(rule_set(&config).thresholds[0].alert_priority, Some(2));
+
+		let config = SSH_FAILURES.replace("priority = 2", "priority = 9");
+		assert!(toml::from_str::<AppSettings>(&format!("[telegram]\nchat_id = \"1\"\n[match]\n[allow]\n{}", config)).is_err());
+	}
+
+	#[test]
+	fn denied_entries_are_not_counted() {
+		let config = SSH_FAILURES.replace("^Connection closed by authenticating user test", "^Failed password for test");
+		let rule_set = rule_set(&config);
+		let mut counts = ThresholdCounts::new();
+
+		for seconds in 0..5 {
+			assert!(matches!(count_entry(&rule_set, &mut counts, &entry(seconds, "Failed password for test")), Threshold::Pass));
+		}
+		assert!(counts.is_empty());
+		assert!(matches!(count_entry(&rule_set, &mut counts, &entry(5, "Failed password for root")), Threshold::Counted));
+	}
+
+	#[test]
+	fn counts_expire_by_entry_time() {
+		let rule_set = rule_set(SSH_FAILURES);
+		let mut counts = ThresholdCounts::new();
+
+		// entries from 2020 are still counted against each other, however long ago that was
+		assert!(matches!(count_entry(&rule_set, &mut counts, &entry(0, "Failed password for root")), Threshold::Counted));
+		assert!(matches!(count_entry(&rule_set, &mut counts, &entry(30, "Failed password for root")), Threshold::Counted));
+		// the first entry is now more than a minute old
+		assert!(matches!(count_entry(&rule_set, &mut counts, &entry(61, "Failed password for root")), Threshold::Counted));
+		assert_eq!(counts[&(2, "sshd".to_string())].len(), 2);
+		assert!(matches!(count_entry(&rule_set, &mut counts, &entry(90, "Failed password for root")), Threshold::Alert(..)));
+	}
+}
@@ -0,0 +1,369 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use chrono::{DateTime, Local, TimeZone};
+use serde_derive::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use crate::config::{AppSettings, HeartbeatSettings};
+use crate::filter::{Route, Rules};
+use crate::helpers::{format_duration, parse_duration, MAX_DURATION};
+use crate::journal::LogEntry;
+use crate::sink::SinkHandle;
+
+static HEARTBEATS: Mutex<Vec<Heartbeat>> = Mutex::new(Vec::new());
+static STATE_FILE: OnceLock<PathBuf> = OnceLock::new();
+/// Set when a heartbeat was seen since the last save, so the periodic check writes it out
+static UNSAVED: AtomicBool = AtomicBool::new(false);
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_PRIORITY: u8 = 3;
+
+/// An entry that is expected to be logged at least once every `every`
+struct Heartbeat {
+	name: String,
+	rules: Rules,
+	every: Duration,
+	priority: u8,
+	route: Route,
+	last_seen: DateTime<Local>,
+	/// When the last missed deadline was alerted, cleared once the entry shows up again
+	alerted_at: Option<DateTime<Local>>,
+}
+
+/// How heartbeats are persisted in the state file, by name
+#[derive(Serialize, Deserialize)]
+struct SavedHeartbeat {
+	last_seen: i64,
+	alerted_at: Option<i64>,
+}
+
+fn timestamp(seconds: i64) -> Option<DateTime<Local>> {
+	Local.timestamp_opt(seconds, 0).single()
+}
+
+fn read_state(path: &PathBuf) -> HashMap<String, SavedHeartbeat> {
+	match std::fs::read_to_string(path) {
+		Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+			eprintln!("[heartbeat] Failed to parse {}: {}", path.display(), e);
+			HashMap::new()
+		}),
+		Err(e) => {
+			if e.kind() != std::io::ErrorKind::NotFound {
+				eprintln!("[heartbeat] Failed to read {}: {}", path.display(), e);
+			}
+			HashMap::new()
+		}
+	}
+}
+
+fn save(heartbeats: &[Heartbeat]) {
+	UNSAVED.store(false, Ordering::Relaxed);
+	if let Some(path) = STATE_FILE.get() {
+		save_to(path, heartbeats);
+	}
+}
+
+fn save_to(path: &PathBuf, heartbeats: &[Heartbeat]) {
+	let saved: BTreeMap<&str, SavedHeartbeat> = heartbeats.iter().map(|heartbeat| (heartbeat.name.as_str(), SavedHeartbeat {
+		last_seen: heartbeat.last_seen.timestamp(),
+		alerted_at: heartbeat.alerted_at.map(|alerted_at| alerted_at.timestamp()),
+	})).collect();
+
+	if let Some(parent) = path.parent() {
+		if let Err(e) = std::fs::create_dir_all(parent) {
+			eprintln!("[heartbeat] Failed to create state directory {}: {}", parent.display(), e);
+			return
+		}
+	}
+
+	let tmp_path = path.with_extension("tmp");
+	let result = std::fs::write(&tmp_path, serde_json::to_string(&saved).unwrap())
+		.and_then(|_| std::fs::rename(&tmp_path, path));
+
+	if let Err(e) = result {
+		eprintln!("[heartbeat] Failed to save heartbeats to {}: {}", path.display(), e);
+	}
+}
+
+/// Write out when each heartbeat was last seen if that changed since the last save, e.g. before exiting
+pub fn sync() {
+	if UNSAVED.load(Ordering::Relaxed) {
+		save(&HEARTBEATS.lock().unwrap());
+	}
+}
+
+/// Carry over when each heartbeat was last seen and alerted from the state file
+fn restore(heartbeats: &mut [Heartbeat], saved: &HashMap<String, SavedHeartbeat>) {
+	for heartbeat in heartbeats.iter_mut() {
+		if let Some(saved) = saved.get(&heartbeat.name) {
+			heartbeat.last_seen = timestamp(saved.last_seen).unwrap_or(heartbeat.last_seen);
+			heartbeat.alerted_at = saved.alerted_at.and_then(timestamp);
+		}
+	}
+}
+
+/// Build the configured heartbeats, all of them or an error listing every invalid one
+fn compile(config: &HeartbeatSettings) -> Result<Vec<Heartbeat>, String> {
+	let mut errors: Vec<String> = Vec::new();
+	let mut loaded: Vec<Heartbeat> = Vec::new();
+
+	let mut names: Vec<&String> = config.rules.keys().collect();
+	names.sort();
+	for name in names {
+		let rule = &config.rules[name];
+		let every = match parse_duration(&rule.every) {
+			Some(every) => every,
+			None => {
+				errors.push(format!("[heartbeat.{}] invalid interval '{}'", name, rule.every));
+				continue
+			}
+		};
+
+		loaded.push(Heartbeat {
+			name: name.clone(),
+			rules: Rules::compile(&format!("[heartbeat.{}]", name), &rule.rules, &mut errors),
+			every,
+			priority: rule.priority.unwrap_or(DEFAULT_PRIORITY),
+			route: match rule.destinations.is_empty() {
+				true => Route::Default,
+				false => Route::To(rule.destinations.clone()),
+			},
+			last_seen: Local::now(),
+			alerted_at: None,
+		});
+	}
+
+	if !errors.is_empty() {
+		for error in errors.iter() {
+			println!("[heartbeat] {}", error);
+		}
+		return Err(errors.join("; "))
+	}
+	Ok(loaded)
+}
+
+/// Check the heartbeats of a config without touching the running ones
+pub fn validate(settings: &AppSettings) -> Result<(), String> {
+	compile(&settings.heartbeat).map(|_| ())
+}
+
+/// Load the heartbeats from the config, carrying over when each was last seen from the running
+/// set or the state file. A heartbeat seen for the first time starts counting from now
+pub fn load(settings: &AppSettings) -> Result<(), String> {
+	let config: &HeartbeatSettings = &settings.heartbeat;
+	if let Some(state_file) = &config.state_file {
+		let _ = STATE_FILE.set(state_file.clone());
+	}
+
+	let mut loaded = compile(config)?;
+	let mut heartbeats = HEARTBEATS.lock().unwrap();
+	if heartbeats.is_empty() {
+		restore(&mut loaded, &STATE_FILE.get().map(read_state).unwrap_or_default());
+	}
+	for heartbeat in loaded.iter_mut() {
+		if let Some(running) = heartbeats.iter().find(|running| running.name == heartbeat.name) {
+			heartbeat.last_seen = running.last_seen;
+			heartbeat.alerted_at = running.alerted_at;
+		}
+	}
+
+	*heartbeats = loaded;
+	if !heartbeats.is_empty() {
+		println!("[heartbeat] Watching {} heartbeats", heartbeats.len());
+		save(&heartbeats);
+	}
+	Ok(())
+}
+
+fn alert(heartbeat: &Heartbeat, priority: u8, message: String) -> (LogEntry, Route) {
+	(LogEntry::new(priority, Local::now(), "telelog".to_string(), message, BTreeMap::new()), heartbeat.route.clone())
+}
+
+/// Record the entry against every heartbeat it matches. Returns a recovery notice for each
+/// heartbeat that had missed its deadline. Only recoveries are saved right away, other sightings
+/// are saved by the periodic check
+pub fn observe(entry: &LogEntry) -> Vec<(LogEntry, Route)> {
+	let mut heartbeats = HEARTBEATS.lock().unwrap();
+	let (seen, recovered) = observe_in(&mut heartbeats, entry);
+
+	if !recovered.is_empty() {
+		save(&heartbeats);
+	} else if seen {
+		UNSAVED.store(true, Ordering::Relaxed);
+	}
+	recovered
+}
+
+/// Returns whether any heartbeat matched, and the recovery notices
+fn observe_in(heartbeats: &mut [Heartbeat], entry: &LogEntry) -> (bool, Vec<(LogEntry, Route)>) {
+	let mut seen = false;
+	let mut recovered = Vec::new();
+
+	for heartbeat in heartbeats.iter_mut().filter(|heartbeat| heartbeat.rules.is_match(entry)) {
+		heartbeat.last_seen = heartbeat.last_seen.max(entry.timestamp);
+		seen = true;
+
+		if heartbeat.alerted_at.take().is_some() {
+			println!("[heartbeat] {} recovered", heartbeat.name);
+			recovered.push(alert(heartbeat, 5, format!("Heartbeat '{}' recovered: {}", heartbeat.name, entry.message)));
+		}
+	}
+	(seen, recovered)
+}
+
+/// Alerts for heartbeats past their deadline, saving the state if any were missed or seen since the last save
+fn missed() -> Vec<(LogEntry, Route)> {
+	let mut heartbeats = HEARTBEATS.lock().unwrap();
+	let alerts = missed_in(&mut heartbeats, Local::now());
+
+	if !alerts.is_empty() || UNSAVED.load(Ordering::Relaxed) {
+		save(&heartbeats);
+	}
+	alerts
+}
+
+/// Alerts for heartbeats past their deadline at `now`, repeated once per missed interval
+fn missed_in(heartbeats: &mut [Heartbeat], now: DateTime<Local>) -> Vec<(LogEntry, Route)> {
+	let mut alerts = Vec::new();
+
+	for heartbeat in heartbeats.iter_mut() {
+		let every = chrono::Duration::from_std(heartbeat.every.min(MAX_DURATION)).expect("a year fits in a chrono::Duration");
+		let due = heartbeat.alerted_at.unwrap_or(heartbeat.last_seen) + every;
+		if now < due {
+			continue
+		}
+
+		let silent_for = (now - heartbeat.last_seen).to_std().unwrap_or_default();
+		println!("[heartbeat] {} missed, last seen {} ago", heartbeat.name, format_duration(silent_for));
+		alerts.push(alert(heartbeat, heartbeat.priority, format!(
+			"Heartbeat '{}' missed: nothing matching for {} (expected every {}), last seen {}",
+			heartbeat.name, format_duration(silent_for), format_duration(heartbeat.every), heartbeat.last_seen.format("%b %d %H:%M:%S"),
+		)));
+		heartbeat.alerted_at = Some(now);
+	}
+	alerts
+}
+
+/// Deliver alerts to the sinks their routes select
+pub async fn send(alerts: Vec<(LogEntry, Route)>, sinks: &[SinkHandle]) {
+	for (entry, route) in alerts {
+		for sink in sinks.iter().filter(|sink| sink.accepts(&route)) {
+			sink.send(entry.clone()).await;
+		}
+	}
+}
+
+/// Periodically check the heartbeats' deadlines, alerting on any that were missed
+pub fn spawn(sinks: Arc<Vec<SinkHandle>>) {
+	tokio::spawn(async move {
+		loop {
+			sleep(CHECK_INTERVAL).await;
+			send(missed(), &sinks).await;
+		}
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::{Rule, RuleLogic, RuleValue};
+
+	fn start() -> DateTime<Local> {
+		Local.with_ymd_and_hms(2024, 3, 1, 2, 0, 0).unwrap()
+	}
+
+	fn backup() -> Heartbeat {
+		let rules = vec![Rule {
+			field: "SYSLOG_IDENTIFIER".to_string(),
+			value: RuleValue::Single("^backup$".to_string()),
+			logic: RuleLogic::Any,
+			destinations: Vec::new(),
+		}];
+		let mut errors = Vec::new();
+		let heartbeat = Heartbeat {
+			name: "backup".to_string(),
+			rules: Rules::compile("[heartbeat.backup]", &rules, &mut errors),
+			every: Duration::from_secs(24 * 60 * 60),
+			priority: DEFAULT_PRIORITY,
+			route: Route::To(vec!["ops".to_string()]),
+			last_seen: start(),
+			alerted_at: None,
+		};
+		assert!(errors.is_empty());
+		heartbeat
+	}
+
+	fn entry(identifier: &str, at: DateTime<Local>) -> LogEntry {
+		LogEntry::new(6, at, identifier.to_string(), "backup finished".to_string(), BTreeMap::new())
+	}
+
+	fn hours(hours: i64) -> chrono::Duration {
+		chrono::Duration::hours(hours)
+	}
+
+	#[test]
+	fn alerts_once_per_missed_interval() {
+		let mut heartbeats = vec![backup()];
+
+		assert!(missed_in(&mut heartbeats, start() + hours(23)).is_empty());
+		let alerts = missed_in(&mut heartbeats, start() + hours(24));
+		assert_eq!(alerts.len(), 1);
+		assert_eq!(alerts[0].0.priority, DEFAULT_PRIORITY);
+		assert!(alerts[0].0.message.starts_with("Heartbeat 'backup' missed: nothing matching for 1d"), "{}", alerts[0].0.message);
+		assert_eq!(alerts[0].1, Route::To(vec!["ops".to_string()]));
+
+		// not again until another interval has passed
+		assert!(missed_in(&mut heartbeats, start() + hours(47)).is_empty());
+		assert_eq!(missed_in(&mut heartbeats, start() + hours(48)).len(), 1);
+	}
+
+	#[test]
+	fn recovers_when_seen_again() {
+		let mut heartbeats = vec![backup()];
+		missed_in(&mut heartbeats, start() + hours(25));
+
+		let (seen, recovered) = observe_in(&mut heartbeats, &entry("cron", start() + hours(26)));
+		assert!(!seen && recovered.is_empty());
+
+		let (seen, recovered) = observe_in(&mut heartbeats, &entry("backup", start() + hours(26)));
+		assert!(seen);
+		assert_eq!(recovered.len(), 1);
+		assert_eq!(recovered[0].0.message, "Heartbeat 'backup' recovered: backup finished");
+		assert_eq!(heartbeats[0].last_seen, start() + hours(26));
+		assert_eq!(heartbeats[0].alerted_at, None);
+
+		// the deadline now counts from the entry
+		assert!(missed_in(&mut heartbeats, start() + hours(49)).is_empty());
+		assert_eq!(missed_in(&mut heartbeats, start() + hours(50)).len(), 1);
+	}
+
+	#[test]
+	fn persists_last_seen_and_alerts() {
+		let path = std::env::temp_dir().join(format!("telelog-heartbeats-{}.json", std::process::id()));
+		let mut heartbeats = vec![backup()];
+		observe_in(&mut heartbeats, &entry("backup", start() + hours(1)));
+		missed_in(&mut heartbeats, start() + hours(30));
+		save_to(&path, &heartbeats);
+
+		let mut restored = vec![backup()];
+		restore(&mut restored, &read_state(&path));
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(restored[0].last_seen, start() + hours(1));
+		assert_eq!(restored[0].alerted_at, Some(start() + hours(30)));
+
+		// an alert that was already sent is not repeated after a restart
+		assert!(missed_in(&mut restored, start() + hours(31)).is_empty());
+	}
+
+	#[test]
+	fn alert_priorities_take_names_and_levels() {
+		let heartbeat = |priority: &str| toml::from_str::<HeartbeatSettings>(&format!("[backup]\nevery = \"26h\"\npriority = {}\nrules = []\n", priority));
+		assert_eq!(heartbeat("\"warning\"").unwrap().rules["backup"].priority, Some(4));
+		assert_eq!(heartbeat("2").unwrap().rules["backup"].priority, Some(2));
+		assert!(heartbeat("9").is_err());
+	}
+}
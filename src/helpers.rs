@@ -1,68 +1,260 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use lazy_static::lazy_static;
+use regex::Regex;
+
 use crate::journal::LogEntry;
+use crate::template::{priority_name, LineFormat};
 
-pub fn generate_messages(buffer: &Vec<LogEntry>) -> Vec<String> {
-	let mut message_list: Vec<String> = vec![];
-	let mut current_message = String::from("<code>\n");
+lazy_static!(
+	// numbers, PIDs and hex IDs: 0x prefixed hex, or any run of hex digits containing a decimal digit
+	static ref VARIABLE_PART: Regex = Regex::new(r"\b(?:0x[0-9a-fA-F]+|[0-9a-fA-F]*[0-9][0-9a-fA-F]*)\b").unwrap();
+);
 
-	for entry in buffer {
-		let new_entry_string = format!("{}[{}] {}: {}\n", colour_translate(entry.priority), entry.timestamp.format("%b %d %H:%M:%S"), entry.identifier, entry.message);
-		
-		if current_message.len() + new_entry_string.len() >= 4088 {
-			current_message.push_str("</code>");
-			message_list.push(current_message);
-			current_message = String::from("<code>\n");
-		}
+/// An entry standing in for itself and any similar ones collapsed into it
+pub struct Repeated<'a> {
+	pub entry: &'a LogEntry,
+	pub count: usize,
+	pub last: DateTime<Local>,
+}
+
+/// Collapse entries from the same identifier and priority whose messages only differ in numbers, PIDs or hex IDs
+/// into the first of them, as long as each follows the previous one within `window`.
+/// Without a window every entry is kept as is
+pub fn collapse_repeats(entries: &[LogEntry], window: Option<Duration>) -> Vec<Repeated<'_>> {
+	let mut collapsed: Vec<Repeated> = Vec::new();
+	let window = match window {
+		Some(window) if !window.is_zero() => window,
+		_ => return entries.iter().map(|entry| Repeated { entry, count: 1, last: entry.timestamp }).collect(),
+	};
 
-		current_message.push_str(&new_entry_string);
+	// index into `collapsed` of the latest run for each identifier, priority and masked message
+	let mut runs: HashMap<(&str, u8, String), usize> = HashMap::new();
+
+	for entry in entries {
+		let key = (entry.identifier.as_str(), entry.priority, VARIABLE_PART.replace_all(&entry.message, "#").into_owned());
+		if let Some(&index) = runs.get(&key) {
+			let run = &mut collapsed[index];
+			if (entry.timestamp - run.last).to_std().unwrap_or_default() <= window {
+				run.count += 1;
+				run.last = run.last.max(entry.timestamp);
+				continue
+			}
+		}
+		runs.insert(key, collapsed.len());
+		collapsed.push(Repeated { entry, count: 1, last: entry.timestamp });
 	}
-	if current_message != "<code>\n" {
-		current_message.push_str("</code>");
-		message_list.push(current_message);
+
+	collapsed
+}
+
+/// Longest text Telegram accepts in one message, in UTF-16 code units once the HTML is parsed
+pub const MESSAGE_LIMIT: usize = 4096;
+
+/// Marks where an entry too long for one message was cut, at the end of one piece and the start of the next
+const CONTINUED: &str = "…";
+
+pub fn utf16_len(text: &str) -> usize {
+	text.encode_utf16().count()
+}
+
+/// Length of an HTML message as Telegram counts it: tags take no room and an entity is one character
+pub fn visible_len(html: &str) -> usize {
+	let mut length = 0;
+	let mut chars = html.chars();
+	while let Some(c) = chars.next() {
+		match c {
+			'<' => { chars.by_ref().find(|&c| c == '>'); },
+			'&' => {
+				chars.by_ref().find(|&c| c == ';');
+				length += 1;
+			},
+			c => length += c.len_utf16(),
+		}
 	}
-	return message_list
+	length
 }
 
-/// Flatten multiple message lists of sparse length to a single list of messages at maximum length
-pub fn flatten_messages(message_lists: [&Vec<String>; 2]) -> Vec<String> {
-	// if two or more messages can be concatenated and still be within the size limit, do it
-	let mut flattened_messages: Vec<String> = Vec::new();
-	
-	for list in message_lists {
-		for message in list {
-			if flattened_messages.len() == 0 {
-				flattened_messages.push(message.clone());
-				continue
-			}
+/// Cut a line into pieces of at most `limit` UTF-16 code units, marked with `CONTINUED` where cut.
+/// Cuts go after the last newline that fits, or between characters if there is none,
+/// a newline at the cut is left out as the piece ends there anyway.
+/// `limit` needs room for a marker at each end and one character, at least 4
+pub fn split_line(line: &str, limit: usize) -> Vec<String> {
+	let marker = utf16_len(CONTINUED);
+	// a surrogate pair needs two units, so a middle piece with markers at both ends needs at least 4
+	debug_assert!(limit >= 2 * marker + 2, "split_line limit {} cannot fit a marked character", limit);
+	let mut pieces = Vec::new();
+	let mut rest = line;
+	let mut rest_length = utf16_len(line);
+	let mut prefix = "";
+
+	while utf16_len(prefix) + rest_length > limit {
+		let room = limit.saturating_sub(utf16_len(prefix) + marker);
 
-			if flattened_messages.last().unwrap().len() + message.len() < 4108 {
-				let mut previous_message: String = flattened_messages.pop().unwrap();
-				
-				previous_message = previous_message
-					.strip_suffix("</code>")
-					.expect("last message did not end with </code>")
-					.to_string();
-				
-				previous_message.push_str(message.strip_prefix("<code>").unwrap_or(""));
-				flattened_messages.push(previous_message);
+		// byte offset of the end of the last character that fits, never less than one character
+		let mut end = 0;
+		let mut units = 0;
+		for (index, c) in rest.char_indices() {
+			if units + c.len_utf16() > room && end > 0 {
+				break
 			}
+			units += c.len_utf16();
+			end = index + c.len_utf8();
+		}
+
+		let (piece, remaining) = match rest[..end].rfind('\n') {
+			Some(newline) if newline > 0 => (&rest[..newline], &rest[newline + 1..]),
+			_ => rest.split_at(end),
+		};
+		pieces.push(format!("{}{}{}", prefix, piece, CONTINUED));
+		rest_length -= utf16_len(&rest[..rest.len() - remaining.len()]);
+		rest = remaining;
+		prefix = CONTINUED;
+	}
+	pieces.push(format!("{}{}", prefix, rest));
+
+	pieces
+}
+
+/// The lines of a batch: the header, one per entry, and the footer
+pub fn batch_lines(buffer: &[Repeated], format: &LineFormat) -> Vec<String> {
+	let mut lines: Vec<String> = Vec::new();
+	if buffer.is_empty() {
+		return lines
+	}
+
+	if let Some(header) = &format.header {
+		lines.push(format.batch_line(header, buffer));
+	}
+	lines.extend(buffer.iter().map(|repeated| format.line(repeated)));
+	if let Some(footer) = &format.footer {
+		lines.push(format.batch_line(footer, buffer));
+	}
+	lines
+}
+
+/// Group lines into as few messages as possible, each line followed by a newline and every
+/// message at most `limit` UTF-16 code units. Lines are never split between messages
+/// unless a single one is too long for a message of its own
+pub fn pack_lines(lines: &[String], limit: usize) -> Vec<Vec<String>> {
+	let mut messages: Vec<Vec<String>> = Vec::new();
+	let mut current: Vec<String> = Vec::new();
+	let mut current_length = 0;
+
+	for piece in lines.iter().flat_map(|line| split_line(line, limit - 1)) {
+		let length = utf16_len(&piece) + 1;
+		if current_length + length > limit {
+			messages.push(std::mem::take(&mut current));
+			current_length = 0;
+		}
+		current.push(piece);
+		current_length += length;
+	}
+	if !current.is_empty() {
+		messages.push(current);
+	}
+	messages
+}
+
+/// Render a batch into as few messages as fit within `MESSAGE_LIMIT`
+pub fn generate_messages(buffer: &[Repeated], format: &LineFormat) -> Vec<String> {
+	// the newline after <code> counts too
+	pack_lines(&batch_lines(buffer, format), MESSAGE_LIMIT - 1).into_iter().map(|lines| {
+		let mut message = String::from("<code>\n");
+		for line in lines {
+			message.push_str(&escape_html(&line));
+			message.push('\n');
+		}
+		message.push_str("</code>");
+		message
+	}).collect()
+}
+
+/// Longest caption Telegram accepts on a document, counted like `MESSAGE_LIMIT`
+pub const CAPTION_LIMIT: usize = 1024;
+
+/// Render a batch as a log file and a caption summarising it: how many entries there are
+/// at each priority and from each identifier, most frequent first
+pub fn generate_document(buffer: &[Repeated], format: &LineFormat) -> (String, String) {
+	let mut text = batch_lines(buffer, format).join("\n");
+	text.push('\n');
+
+	let mut priorities: BTreeMap<u8, usize> = BTreeMap::new();
+	let mut identifiers: HashMap<&str, usize> = HashMap::new();
+	for repeated in buffer {
+		*priorities.entry(repeated.entry.priority).or_default() += repeated.count;
+		*identifiers.entry(repeated.entry.identifier.as_str()).or_default() += repeated.count;
+	}
+	let mut identifiers: Vec<(&str, usize)> = identifiers.into_iter().collect();
+	identifiers.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+	let total: usize = priorities.values().sum();
+	let first = buffer.iter().map(|repeated| repeated.entry.timestamp).min();
+	let last = buffer.iter().map(|repeated| repeated.last).max();
+	let mut caption = match (first, last) {
+		(Some(first), Some(last)) => format!("<b>{} entries</b>, {} to {}\n", total, escape_html(&format.timestamp(&first)), escape_html(&format.timestamp(&last))),
+		_ => format!("<b>{} entries</b>\n", total),
+	};
+	let counts: Vec<String> = priorities.iter()
+		.map(|(priority, count)| match format.mark(*priority) {
+			mark if mark.is_empty() => format!("{}: {}", priority_name(*priority), count),
+			mark => format!("{} {}: {}", mark, priority_name(*priority), count),
+		})
+		.collect();
+	caption.push_str(&escape_html(&counts.join(", ")));
+	caption.push('\n');
+
+	// as many identifiers as fit, leaving room to say how many were left out
+	let room = CAPTION_LIMIT - 20;
+	for (index, (identifier, count)) in identifiers.iter().enumerate() {
+		let separator = if index == 0 { "" } else { ", " };
+		let item = escape_html(&format!("{}{}: {}", separator, identifier, count));
+		if visible_len(&caption) + visible_len(&item) > room {
+			caption.push_str(&format!(", and {} more", identifiers.len() - index));
+			break
 		}
+		caption.push_str(&item);
 	}
 
-	return flattened_messages
+	(caption, text)
+}
+
+/// Concatenate two consecutive messages into one if the result is still within the size limit
+/// and both are wrapped in `<code>`
+pub fn combine_messages(previous: &str, next: &str) -> Option<String> {
+	if visible_len(previous) + visible_len(next) > MESSAGE_LIMIT {
+		return None
+	}
+
+	let mut combined = previous.strip_suffix("</code>")?.to_string();
+	combined.push_str(next.strip_prefix("<code>")?);
+	Some(combined)
+}
+
+/// Whether an HTTP error status means the destination will never accept the message as it is.
+/// That is any 4xx but 408 Request Timeout and 429 Too Many Requests, the rest are worth retrying
+pub fn is_permanent_failure(status: reqwest::StatusCode) -> bool {
+	status.is_client_error() && !matches!(status.as_u16(), 408 | 429)
+}
+
+/// RGB colour of a priority, for destinations that colour-code messages
+pub fn colour_hex(priority: u8) -> u32 {
+	match priority {
+		0 => 0xFF3333,
+		1 => 0xFF6600,
+		2 => 0x800080,
+		3 => 0xB22222,
+		4 => 0xFFD700,
+		5 => 0x87CEEB,
+		6 => 0x4169E1,
+		7 => 0xCDD1D3,
+		_ => 0x000000,
+	}
 }
 
 pub fn colour_translate(priority: u8) -> String {
-	// match priority {
-	// 	0 => "#FF3333".to_owned(),
-	// 	1 => "#FF6600".to_owned(),
-	// 	2 => "#800080".to_owned(),
-	// 	3 => "#B22222".to_owned(),
-	// 	4 => "#FFD700".to_owned(),
-	// 	5 => "#87CEEB".to_owned(),
-	// 	6 => "#4169E1".to_owned(),
-	// 	7 => "#CDD1D3".to_owned(),
-	// 	_ => "#000000".to_owned(),
-	// }
 	match priority {
 		0 => "☢️".to_owned(),
 		1 => "‼️".to_owned(),
@@ -87,15 +279,354 @@ pub fn escape_message(message: &str) -> String {
 		message = message.strip_suffix("</code>").unwrap_or("").to_string();
 	}
 
-	message = message
-		.replace("&", "&amp;")
-		.replace("<", "&lt;")
-		.replace(">", "&gt;")
-		.replace("\"", "&quot;")
-		.replace("'", "&#39;");
+	"<code>".to_string() + &escape_html(&message) + "</code>"
+}
 
+/// Escape text for Telegram's HTML parse mode, so it shows literally
+pub fn escape_html(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for c in text.chars() {
+		match c {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			'\'' => escaped.push_str("&#39;"),
+			c => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+/// Undo `escape_html`
+pub fn unescape_html(text: &str) -> String {
+	text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&#39;", "'").replace("&amp;", "&")
+}
 
-	message = "<code>".to_string() + &message + "</code>";
+/// Escape a message Telegram could not parse. Text that is already escaped is unescaped first,
+/// so escaping a message twice gives the same text as escaping it once
+pub fn reescape_message(message: &str) -> String {
+	escape_message(&unescape_html(message))
+}
+
+/// Longest rate limit pause honoured, whatever a destination asks for
+pub const MAX_PAUSE: Duration = Duration::from_secs(3600);
+
+/// Longest duration `parse_duration` accepts
+pub const MAX_DURATION: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Parse a duration such as `90s`, `15m`, `2h`, `1d` or `1h30m`, up to a year
+pub fn parse_duration(text: &str) -> Option<Duration> {
+	let mut total: u64 = 0;
+	let mut number = String::new();
+
+	for c in text.trim().chars() {
+		if c.is_ascii_digit() {
+			number.push(c);
+			continue
+		}
+
+		let value: u64 = number.parse().ok()?;
+		number.clear();
+		let unit = match c {
+			's' => 1,
+			'm' => 60,
+			'h' => 60 * 60,
+			'd' => 24 * 60 * 60,
+			'w' => 7 * 24 * 60 * 60,
+			_ => return None,
+		};
+		total = total.checked_add(value.checked_mul(unit)?)?;
+	}
 
-	message
-}
\ No newline at end of file
+	// a bare number is taken as minutes
+	if !number.is_empty() {
+		total = total.checked_add(number.parse::<u64>().ok()?.checked_mul(60)?)?;
+	}
+
+	if total == 0 || total > MAX_DURATION.as_secs() {
+		return None
+	}
+	Some(Duration::from_secs(total))
+}
+
+/// Format a duration as a short human readable string, e.g. `3d 4h 12m`
+pub fn format_duration(duration: Duration) -> String {
+	let seconds = duration.as_secs();
+	let (days, hours, minutes) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60);
+
+	if days > 0 {
+		format!("{}d {}h {}m", days, hours, minutes)
+	} else if hours > 0 {
+		format!("{}h {}m", hours, minutes)
+	} else if minutes > 0 {
+		format!("{}m {}s", minutes, seconds % 60)
+	} else {
+		format!("{}s", seconds)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+
+	use chrono::Local;
+
+	use proptest::prelude::*;
+
+	use super::*;
+	use crate::config::FormatSettings;
+
+	const ADVERSARIAL: &[&str] = &[
+		"<script>alert(1)</script>",
+		"</code><b>not bold</b><code>",
+		"a && b || c < d > e",
+		"already escaped: &amp; &lt;b&gt; &#60; &#x3C;",
+		"quotes ' and \" and `ticks`",
+		"<",
+		"trailing &",
+		"<a href=\"https://example.com\">link</a>",
+		"<pre><code class=\"language-rust\">x</code></pre>",
+		"emoji 🔥 & ünïcödé <ok>",
+		"&nbsp;&copy;&unknown;",
+	];
+
+	fn entry(identifier: &str, message: &str) -> LogEntry {
+		let fields = BTreeMap::from([("_HOSTNAME".to_string(), "<host&name>".to_string())]);
+		LogEntry::new(3, Local::now(), identifier.to_string(), message.to_string(), fields)
+	}
+
+	/// Telegram's HTML mode only knows a handful of named entities, anything else is a 400
+	fn assert_valid_html(message: &str) {
+		let body = message.strip_prefix("<code>").and_then(|body| body.strip_suffix("</code>"))
+			.unwrap_or_else(|| panic!("not wrapped in <code>: {}", message));
+		assert!(!body.contains('<') && !body.contains('>'), "unescaped tag in {}", message);
+		for (index, _) in body.match_indices('&') {
+			let rest = &body[index..];
+			assert!(["&amp;", "&lt;", "&gt;", "&quot;", "&#39;"].iter().any(|entity| rest.starts_with(entity)), "bare & in {}", message);
+		}
+	}
+
+	#[test]
+	fn escape_html_round_trips() {
+		for text in ADVERSARIAL {
+			let escaped = escape_html(text);
+			assert_valid_html(&format!("<code>{}</code>", escaped));
+			assert_eq!(unescape_html(&escaped), *text);
+		}
+	}
+
+	#[test]
+	fn masks_numbers_pids_and_hex_ids() {
+		let mask = |message: &str| VARIABLE_PART.replace_all(message, "#").into_owned();
+		assert_eq!(mask("sshd[1234]: session 42 opened"), "sshd[#]: session # opened");
+		assert_eq!(mask("fault at 0xDEADbeef in 7f3a9c01"), "fault at # in #");
+		assert_eq!(mask("job 3fa9c2e1-0b7d-4c1e finished"), "job #-#-# finished");
+		// words made only of hex letters are kept, so "added" or "face" don't turn into IDs
+		assert_eq!(mask("added a cafe to the database"), "added a cafe to the database");
+		assert_eq!(mask("ipv4 and http2"), "ipv4 and http2");
+	}
+
+	#[test]
+	fn collapses_repeats_within_the_window() {
+		let window = Duration::from_secs(60);
+		let start = Local::now();
+		let at = |seconds: i64, priority: u8, message: &str| {
+			let mut entry = entry("app", message);
+			entry.timestamp = start + chrono::Duration::seconds(seconds);
+			entry.priority = priority;
+			entry
+		};
+
+		let entries = vec![
+			at(0, 3, "worker 1 died"),
+			at(60, 3, "worker 2 died"),
+			// exactly one window after the previous repeat, not the first
+			at(120, 3, "worker 3 died"),
+			// same message at another priority starts its own run
+			at(121, 4, "worker 4 died"),
+			// just past the window
+			at(181, 3, "worker 5 died"),
+		];
+		let collapsed = collapse_repeats(&entries, Some(window));
+
+		let runs: Vec<(&str, usize)> = collapsed.iter().map(|repeated| (repeated.entry.message.as_str(), repeated.count)).collect();
+		assert_eq!(runs, vec![("worker 1 died", 3), ("worker 4 died", 1), ("worker 5 died", 1)]);
+		assert_eq!(collapsed[0].last, entries[2].timestamp);
+		assert_eq!(collapse_repeats(&entries, None).len(), entries.len());
+	}
+
+	#[test]
+	fn reescaping_is_idempotent() {
+		for text in ADVERSARIAL {
+			let once = reescape_message(&format!("<code>{}</code>", text));
+			assert_valid_html(&once);
+			assert_eq!(reescape_message(&once), once);
+			assert_eq!(reescape_message(&escape_message(text)), escape_message(text));
+		}
+	}
+
+	#[test]
+	fn combines_only_code_blocks() {
+		assert_eq!(combine_messages("<code>a</code>", "<code>b</code>").as_deref(), Some("<code>ab</code>"));
+		assert_eq!(combine_messages("<b>a</b>", "<code>b</code>"), None);
+		assert_eq!(combine_messages("<code>a</code>", "b"), None);
+	}
+
+	#[test]
+	fn parses_durations_up_to_a_year() {
+		assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+		assert_eq!(parse_duration("1h30m"), Some(Duration::from_secs(5400)));
+		assert_eq!(parse_duration("15"), Some(Duration::from_secs(900)));
+		assert_eq!(parse_duration("365d"), Some(Duration::from_secs(365 * 86400)));
+		assert_eq!(parse_duration("366d"), None);
+		assert_eq!(parse_duration("0s"), None);
+		assert_eq!(parse_duration("5x"), None);
+		// past u64 once multiplied, or once added up
+		assert_eq!(parse_duration("99999999999999999d"), None);
+		assert_eq!(parse_duration("18446744073709551615s1s"), None);
+		assert_eq!(parse_duration("99999999999999999999"), None);
+	}
+
+	#[test]
+	fn escapes_every_entry_when_built() {
+		let entries: Vec<LogEntry> = ADVERSARIAL.iter().map(|message| entry("<sshd>", message)).collect();
+		let settings = FormatSettings {
+			template: Some("{emoji}[{timestamp}] {_HOSTNAME} {identifier}: {message}".to_string()),
+			header: Some("{count} from {hostname}".to_string()),
+			..Default::default()
+		};
+		let format = LineFormat::new(&settings, "default").unwrap();
+
+		let messages = generate_messages(&collapse_repeats(&entries, None), &format);
+		assert_eq!(messages.len(), 1);
+		assert_valid_html(&messages[0]);
+
+		let text = unescape_html(&messages[0]["<code>".len()..messages[0].len() - "</code>".len()]);
+		assert!(text.starts_with(&format!("\n{} from <host&name>\n", ADVERSARIAL.len())));
+		for message in ADVERSARIAL {
+			assert!(text.contains(&format!(" <host&name> <sshd>: {}\n", message)), "missing {}", message);
+		}
+	}
+
+	#[test]
+	fn measures_messages_after_escaping() {
+		// each line more than quadruples in size once escaped, but Telegram counts it as parsed
+		let entries: Vec<LogEntry> = (0..20).map(|_| entry("test", &"<&>".repeat(100))).collect();
+		let messages = generate_messages(&collapse_repeats(&entries, None), &LineFormat::default());
+
+		assert!(messages.len() > 1);
+		for message in messages.iter() {
+			assert!(visible_len(message) <= MESSAGE_LIMIT, "message of {} characters", visible_len(message));
+			assert_valid_html(message);
+		}
+		let lines: usize = messages.iter().map(|message| unescape_html(message).matches(&"<&>".repeat(100)).count()).sum();
+		assert_eq!(lines, 20);
+	}
+
+	/// Text heavy in what makes splitting hard: newlines, markup, and characters outside the BMP
+	fn text(max_len: usize) -> impl Strategy<Value = String> {
+		let c = prop_oneof![
+			4 => prop::sample::select(vec!['a', 'z', ' ', '\n', '<', '>', '&', '"', '\'', 'é', '中', '…']),
+			2 => prop::sample::select(vec!['😀', '🔥', '𝄞']),
+			1 => any::<char>(),
+		];
+		// runs of one character keep long texts cheap to generate and shrink
+		prop::collection::vec((c, 1usize..64), 0..max_len / 16)
+			.prop_map(|runs| runs.into_iter().map(|(c, count)| c.to_string().repeat(count)).collect())
+	}
+
+	/// Whether `pieces` put back together, markers removed and newlines at cuts restored, give `line`
+	fn rebuilds(line: &str, pieces: &[String]) -> bool {
+		let last = pieces.len() - 1;
+		let mut content = Vec::new();
+		for (index, piece) in pieces.iter().enumerate() {
+			let mut piece = piece.as_str();
+			if index > 0 {
+				piece = piece.strip_prefix(CONTINUED).expect("continuation not marked");
+			}
+			if index < last {
+				piece = piece.strip_suffix(CONTINUED).expect("cut not marked");
+			}
+			content.push(piece);
+		}
+		matches(line, &content)
+	}
+
+	/// Whether `pieces` follow each other through `line`, each optionally after a newline left out at a cut.
+	/// Tracks every offset reachable so far instead of backtracking, which is exponential on runs of newlines
+	fn matches(line: &str, pieces: &[&str]) -> bool {
+		let mut offsets = vec![0];
+		for (index, piece) in pieces.iter().enumerate() {
+			let mut next = Vec::new();
+			for &offset in offsets.iter() {
+				let rest = &line[offset..];
+				if rest.starts_with(piece) {
+					next.push(offset + piece.len());
+				}
+				if index > 0 && rest.starts_with('\n') && rest[1..].starts_with(piece) {
+					next.push(offset + 1 + piece.len());
+				}
+			}
+			next.sort();
+			next.dedup();
+			offsets = next;
+		}
+		offsets.contains(&line.len())
+	}
+
+	proptest! {
+		#[test]
+		fn split_line_fits_and_loses_nothing(line in text(12000), limit in 4usize..5000) {
+			let pieces = split_line(&line, limit);
+			for piece in pieces.iter() {
+				prop_assert!(utf16_len(piece) <= limit, "piece of {} code units over {}", utf16_len(piece), limit);
+			}
+			if utf16_len(&line) <= limit {
+				prop_assert_eq!(&pieces, &vec![line.clone()]);
+			}
+			prop_assert!(rebuilds(&line, &pieces));
+		}
+
+		#[test]
+		fn messages_fit_and_keep_every_line(messages in prop::collection::vec(text(6000), 1..12)) {
+			let entries: Vec<LogEntry> = messages.iter().map(|message| entry("test", message)).collect();
+			let repeated = collapse_repeats(&entries, None);
+			let format = LineFormat::default();
+			let generated = generate_messages(&repeated, &format);
+
+			let mut bodies = String::new();
+			for message in generated.iter() {
+				assert_valid_html(message);
+				let body = unescape_html(&message["<code>".len()..message.len() - "</code>".len()]);
+				prop_assert!(visible_len(message) <= MESSAGE_LIMIT, "message of {} code units", visible_len(message));
+				prop_assert_eq!(visible_len(message), utf16_len(&body));
+				bodies.push_str(body.strip_prefix('\n').unwrap());
+			}
+
+			let expected: String = repeated.iter()
+				.flat_map(|repeated| split_line(&format.line(repeated), MESSAGE_LIMIT - 2))
+				.map(|piece| piece + "\n")
+				.collect();
+			prop_assert_eq!(bodies, expected);
+		}
+
+		#[test]
+		fn combined_messages_fit(first in text(5000), second in text(5000)) {
+			let format = LineFormat::default();
+			let entries = [entry("test", &first), entry("test", &second)];
+			let previous = generate_messages(&collapse_repeats(&entries[..1], None), &format);
+			let next = generate_messages(&collapse_repeats(&entries[1..], None), &format);
+			let (previous, next) = (previous.last().unwrap(), &next[0]);
+
+			match combine_messages(previous, next) {
+				Some(combined) => {
+					assert_valid_html(&combined);
+					prop_assert!(visible_len(&combined) <= MESSAGE_LIMIT);
+					prop_assert_eq!(visible_len(&combined), visible_len(previous) + visible_len(next));
+				},
+				None => prop_assert!(visible_len(previous) + visible_len(next) > MESSAGE_LIMIT),
+			}
+		}
+	}
+}
@@ -1,17 +1,252 @@
 use std::collections::BTreeMap;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
 
 use chrono::{DateTime,Local};
 use systemd::{journal, Journal};
 
-pub fn open_journal_tail() -> Journal {
-	let mut j = journal::OpenOptions::default().open().expect("Could not open journal");
-	
+use crate::config::{JournalSettings, DEFAULT_MAX_CATCHUP};
+
+static CURSOR_FILE: OnceLock<PathBuf> = OnceLock::new();
+static SOURCE: OnceLock<JournalSettings> = OnceLock::new();
+static CURSOR_TRACKER: Mutex<CursorTracker> = Mutex::new(CursorTracker::new());
+
+/// Tracks entries in journal order until every sink they were dispatched to has delivered them,
+/// so the saved cursor never moves past an entry that is still waiting to be sent
+struct CursorTracker {
+	next_seq: u64,
+	outstanding: BTreeMap<u64, (String, usize)>,
+	delivered: Option<String>,
+	saved: bool,
+}
+
+impl CursorTracker {
+	const fn new() -> Self {
+		CursorTracker {
+			next_seq: 0,
+			outstanding: BTreeMap::new(),
+			delivered: None,
+			saved: true,
+		}
+	}
+
+	/// Pop every leading entry with no deliveries left, remembering the newest cursor popped
+	fn advance(&mut self) {
+		while let Some(entry) = self.outstanding.first_entry() {
+			if entry.get().1 > 0 {
+				break
+			}
+			self.delivered = Some(entry.remove().0);
+			self.saved = false;
+		}
+	}
+
+	fn save(&mut self) {
+		if self.saved {
+			return
+		}
+		if let Some(cursor) = &self.delivered {
+			save_cursor(cursor);
+		}
+		self.saved = true;
+	}
+}
+
+/// Register an entry that has been dispatched to `deliveries` sinks. Returns the sequence number
+/// the sinks acknowledge it with through `ack_entry`
+pub fn track_entry(cursor: Option<String>, deliveries: usize) -> Option<u64> {
+	let cursor = cursor?;
+	let mut tracker = CURSOR_TRACKER.lock().unwrap();
+	let seq = tracker.next_seq;
+	tracker.next_seq += 1;
+	tracker.outstanding.insert(seq, (cursor, deliveries));
+
+	// entries nobody has to deliver move the cursor along too, but are only written out with
+	// the next delivery to avoid a disk write for every filtered line
+	if deliveries == 0 {
+		tracker.advance();
+	}
+	Some(seq)
+}
+
+/// Mark entries as delivered by one sink, moving the saved cursor forward where possible
+pub fn ack_entries(seqs: &[u64]) {
+	if seqs.is_empty() {
+		return
+	}
+
+	let mut tracker = CURSOR_TRACKER.lock().unwrap();
+	for seq in seqs {
+		if let Some((_, deliveries)) = tracker.outstanding.get_mut(seq) {
+			*deliveries = deliveries.saturating_sub(1);
+		}
+	}
+
+	tracker.advance();
+	tracker.save();
+}
+
+/// Write out any cursor progress that has not been saved yet
+pub fn sync_cursor() {
+	CURSOR_TRACKER.lock().unwrap().save();
+}
+
+/// Choose which journal is read from, the local system journal unless a directory,
+/// files or namespace are configured. Also used by `/tail` and the test subcommand
+pub fn set_source(settings: &JournalSettings) {
+	SOURCE.set(settings.clone()).expect("Initialisation only occurs once");
+}
+
+fn open_source() -> Result<Journal, String> {
+	let default = JournalSettings::default();
+	let settings = SOURCE.get().unwrap_or(&default);
+	let user_only = settings.user_only.unwrap_or(false);
+
+	let result = if !settings.files.is_empty() {
+		journal::OpenFilesOptions::default()
+			.open_files(settings.files.iter().map(|file| file.as_os_str().as_bytes()))
+	} else if let Some(directory) = &settings.directory {
+		journal::OpenDirectoryOptions::default()
+			.current_user(user_only)
+			.open_directory(directory.as_os_str().as_bytes())
+	} else if let Some(namespace) = &settings.namespace {
+		journal::OpenOptions::default()
+			.current_user(user_only)
+			.open_namespace(namespace.as_str())
+	} else {
+		journal::OpenOptions::default()
+			.current_user(user_only)
+			.open()
+	};
+
+	result.map_err(|e| format!("Could not open journal: {}", e))
+}
+
+pub fn open_journal(settings: &JournalSettings) -> Journal {
+	set_source(settings);
+	open_source().expect("Could not open journal")
+}
+
+fn seek_tail(j: &mut Journal) {
 	j.seek_tail().expect("[open_journal] Failed to seek to tail");
 
 	println!("[open_journal] Seeked to tail ...\n");
 	j.wait(None).expect("[open_journal] Failed to wait for last entry");
 	j.previous().expect("[open_journal] Failed to position cursor for following tail");
-	j
+}
+
+/// Read the last `count` entries logged by a systemd unit, oldest first
+pub fn read_unit_tail(unit: &str, count: usize) -> Result<Vec<journal::JournalRecord>, String> {
+	let mut j = open_source()?;
+	j.match_add("_SYSTEMD_UNIT", unit).map_err(|e| format!("Could not match unit: {}", e))?;
+	j.seek_tail().map_err(|e| format!("Could not seek to tail: {}", e))?;
+
+	let mut records = Vec::new();
+	while records.len() < count {
+		match j.previous_entry() {
+			Ok(Some(record)) => records.push(record),
+			Ok(None) => break,
+			Err(e) => return Err(format!("Could not read journal: {}", e)),
+		}
+	}
+	records.reverse();
+	Ok(records)
+}
+
+/// Read the entry a cursor points at
+pub fn read_cursor_entry(cursor: &str) -> Result<journal::JournalRecord, String> {
+	let mut j = open_source()?;
+	j.seek_cursor(cursor).map_err(|e| format!("Could not seek to cursor: {}", e))?;
+
+	match j.next_entry() {
+		Ok(Some(record)) if j.test_cursor(cursor).unwrap_or(false) => Ok(record),
+		Ok(_) => Err(format!("No entry found for cursor {}", cursor)),
+		Err(e) => Err(format!("Could not read journal: {}", e)),
+	}
+}
+
+/// Position the journal so that the next entry read is the first one not yet delivered.
+/// Resumes from the saved cursor when there is one, replaying at most `max_catchup` entries,
+/// otherwise follows the tail
+pub fn seek_start(j: &mut Journal, settings: &JournalSettings) {
+	let max_catchup = settings.max_catchup.unwrap_or(DEFAULT_MAX_CATCHUP);
+
+	let state_file = match &settings.state_file {
+		Some(path) => path,
+		None => return seek_tail(j),
+	};
+	CURSOR_FILE.set(state_file.clone()).expect("Initialisation only occurs once");
+
+	let cursor = match load_cursor(state_file) {
+		Some(cursor) => cursor,
+		None => {
+			println!("[open_journal] No saved cursor in {}", state_file.display());
+			return seek_tail(j);
+		},
+	};
+
+	if max_catchup == 0 {
+		return seek_tail(j);
+	}
+
+	if j.seek_cursor(cursor.as_str()).is_err() || j.next().is_err() || !j.test_cursor(cursor.as_str()).unwrap_or(false) {
+		println!("[open_journal] Saved cursor is no longer in the journal, replaying the last {} entries", max_catchup);
+		return seek_catchup_limit(j, max_catchup);
+	}
+
+	// count how far behind the tail the saved cursor is, without going past the limit
+	let behind = j.next_skip(max_catchup + 1).unwrap_or(0);
+	if behind > max_catchup {
+		println!("[open_journal] More than {} entries since saved cursor, skipping the oldest", max_catchup);
+		return seek_catchup_limit(j, max_catchup);
+	}
+
+	j.seek_cursor(cursor.as_str()).expect("[open_journal] Failed to seek to saved cursor");
+	j.next().expect("[open_journal] Failed to position cursor on saved entry");
+	println!("[open_journal] Resuming from saved cursor, {} entries to catch up ...\n", behind);
+}
+
+fn seek_catchup_limit(j: &mut Journal, max_catchup: u64) {
+	j.seek_tail().expect("[open_journal] Failed to seek to tail");
+	j.previous_skip(max_catchup + 1).expect("[open_journal] Failed to position cursor for catch up");
+}
+
+fn load_cursor(path: &Path) -> Option<String> {
+	match std::fs::read_to_string(path) {
+		Ok(cursor) if !cursor.trim().is_empty() => Some(cursor.trim().to_string()),
+		Ok(_) => None,
+		Err(e) => {
+			if e.kind() != std::io::ErrorKind::NotFound {
+				eprintln!("[journal] Failed to read cursor file {}: {}", path.display(), e);
+			}
+			None
+		},
+	}
+}
+
+/// Persist the cursor of the last delivered entry, so a restart resumes after it
+fn save_cursor(cursor: &str) {
+	let path = match CURSOR_FILE.get() {
+		Some(path) => path,
+		None => return,
+	};
+
+	if let Some(parent) = path.parent() {
+		if let Err(e) = std::fs::create_dir_all(parent) {
+			eprintln!("[journal] Failed to create state directory {}: {}", parent.display(), e);
+			return
+		}
+	}
+
+	// write to a temporary file and rename over the old one so a crash never leaves a partial cursor
+	let tmp_path = path.with_extension("tmp");
+	let result = std::fs::write(&tmp_path, cursor)
+		.and_then(|_| std::fs::rename(&tmp_path, path));
+
+	if let Err(e) = result {
+		eprintln!("[journal] Failed to save cursor to {}: {}", path.display(), e);
+	}
 }
 
 #[derive(Debug, Clone, Default)]
@@ -20,20 +255,30 @@ pub struct LogEntry {
 	pub timestamp: DateTime<Local>,
 	pub identifier: String,
 	pub message: String,
+	pub cursor: Option<String>,
+	/// Sequence number assigned by `track_entry`, acknowledged once the entry is delivered
+	pub seq: Option<u64>,
 	raw_fields: BTreeMap<String, String>,
 }
 
 impl LogEntry {
 	pub fn new(priority: u8, timestamp: DateTime<Local>, identifier: String, message: String, raw_fields: BTreeMap<String, String>) -> Self {
 		LogEntry {
-			priority: priority,
-			timestamp: timestamp,
-			identifier: identifier,
-			message: message,
-			raw_fields: raw_fields,
+			priority,
+			timestamp,
+			identifier,
+			message,
+			cursor: None,
+			seq: None,
+			raw_fields,
 		}
 	}
 
+	/// Every field read from the journal, by name
+	pub fn raw_fields(&self) -> &BTreeMap<String, String> {
+		&self.raw_fields
+	}
+
 	pub fn get_field(&self, field_string: &str) -> Result<String, String> {
 		match field_string {
 			"PRIORITY" => Ok(self.priority.to_string()),
@@ -1,59 +1,242 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
 
+use signal_hook::{consts::{SIGHUP,SIGTERM,SIGINT}, iterator::Signals};
 use systemd::journal::Journal as SysJournal;
 use systemd::journal as sysjournal;
 
 mod journal;
-use journal::{open_journal_tail, LogEntry};
+use journal::{open_journal, seek_start, sync_cursor, track_entry};
 
 mod config;
-use config::{read_config, AppSettings, parse_cli_args};
+use config::{apply_journal_args, read_config, AppSettings, parse_cli_args};
 
 mod parser;
 use parser::parse_message;
 
 mod filter;
-use filter::filter_log_entry;
-use tokio::sync::mpsc;
+use filter::{count_log_entry, route_log_entry, routed_destinations, Threshold};
+
+mod sink;
+use sink::SinkHandle;
+
+mod spool;
 
 mod telegram;
+mod slack;
+mod discord;
+mod matrix;
+mod smtp;
+mod webhook;
+
 mod helpers;
+mod template;
+use template::Formats;
 
-async fn process_entry(entry: sysjournal::JournalRecord, telegram_tx: &mpsc::Sender<LogEntry>) {
-	match parse_message(entry) {
-		Some(entry) => {
-			if filter_log_entry(&entry) {
-				return
-			}
-			match telegram_tx.send(entry).await {
-				Ok(_) => {},
-				Err(e) => println!("[process_entry] Error in message channel: {}", e),
-			};
+mod mute;
+use mute::is_muted;
+
+mod heartbeat;
+
+mod bot;
+mod check;
+mod explain;
+
+#[cfg(test)]
+mod mock_http;
+#[cfg(test)]
+mod mock_smtp;
+
+/// Set by SIGHUP, the main loop reloads the config when it sees it
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+async fn process_entry(entry: sysjournal::JournalRecord, cursor: Option<String>, sinks: &[SinkHandle]) {
+	let entry = match parse_message(entry) {
+		Ok(entry) => entry,
+		Err(e) => {
+			eprintln!("[main] Skipping malformed entry: {}", e);
+			track_entry(cursor, 0);
+			return
+		}
+	};
+
+	// heartbeats see every entry, even ones that are muted or filtered out
+	heartbeat::send(heartbeat::observe(&entry), sinks).await;
+
+	if is_muted(&entry) {
+		track_entry(cursor, 0);
+		return
+	}
+
+	let (mut entry, route) = match count_log_entry(&entry) {
+		Threshold::Pass => {
+			let route = route_log_entry(&entry);
+			(entry, route)
+		},
+		Threshold::Counted => {
+			track_entry(cursor, 0);
+			return
 		},
-		None => {},
+		Threshold::Alert(alert, route) => (alert, route),
+	};
+	let targets: Vec<&SinkHandle> = sinks.iter().filter(|sink| sink.accepts(&route)).collect();
+
+	entry.seq = track_entry(cursor.clone(), targets.len());
+	entry.cursor = cursor;
+	for sink in targets {
+		sink.send(entry.clone()).await;
 	}
 }
 
-async fn process_batch(j: &mut SysJournal, telegram_tx: &mpsc::Sender<LogEntry>) {
-	if let Ok(Some(entry)) = j.next_entry() {
-		process_entry(entry, telegram_tx).await;
+async fn process_batch(j: &mut SysJournal, sinks: &[SinkHandle]) {
+	while let Ok(Some(entry)) = j.next_entry() {
+		let cursor = j.cursor().ok();
+		process_entry(entry, cursor, sinks).await;
 	}
+}
 
-	loop {
-		if let Ok(Some(next)) = j.next_entry() {
-			process_entry(next, telegram_tx).await;
-		} else {
-			break;
+fn init(settings: &AppSettings) -> (SysJournal, Arc<Vec<SinkHandle>>) {
+	let mut j = open_journal(&settings.journal);
+	if let Err(e) = filter::init(settings, &mut j) {
+		println!("[main] Invalid rules: {}", e);
+		std::process::exit(1);
+	}
+	seek_start(&mut j, &settings.journal);
+
+	let formats = match Formats::new(&settings.format) {
+		Ok(formats) => formats,
+		Err(e) => {
+			println!("[main] Invalid format: {}", e);
+			std::process::exit(1);
+		}
+	};
+
+	let mut sinks: Vec<SinkHandle> = Vec::new();
+	sinks.extend(telegram::sinks(&settings.telegram, &formats).into_iter().map(|sink| sink::spawn(sink, &settings.spool)));
+	sinks.extend(slack::sinks(&settings.slack, &formats).into_iter().map(|sink| sink::spawn(sink, &settings.spool)));
+	sinks.extend(discord::sinks(&settings.discord, &formats).into_iter().map(|sink| sink::spawn(sink, &settings.spool)));
+	sinks.extend(matrix::sinks(&settings.matrix, &formats).into_iter().map(|sink| sink::spawn(sink, &settings.spool)));
+	match smtp::sinks(&settings.smtp, &formats) {
+		Ok(smtp) => sinks.extend(smtp.into_iter().map(|sink| sink::spawn(sink, &settings.spool))),
+		Err(e) => {
+			println!("[main] Invalid [smtp] settings: {}", e);
+			std::process::exit(1);
+		}
+	}
+	sinks.extend(webhook::sinks(&settings.webhook).into_iter().map(|sink| sink::spawn(sink, &settings.spool)));
+	let sinks: Arc<Vec<SinkHandle>> = Arc::new(sinks);
+
+	if let Err(e) = check_destinations(settings, &sinks) {
+		println!("[main] {}", e);
+		std::process::exit(1);
+	}
+
+	mute::init(&settings.mute);
+	mute::spawn_control_socket(&settings.mute);
+
+	if let Err(e) = heartbeat::load(settings) {
+		println!("[main] Invalid heartbeats: {}", e);
+		std::process::exit(1);
+	}
+	heartbeat::spawn(sinks.clone());
+
+	if settings.telegram.commands.unwrap_or(false) {
+		bot::spawn(&settings.telegram, sinks.clone());
+	}
+
+	// task to handle incoming signals
+	let signal_sinks = sinks.clone();
+	tokio::spawn(async move {
+		let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP]).unwrap();
+		for signal in signals.forever() {
+			match signal {
+				SIGHUP => {
+					println!("[main] Received SIGHUP, reloading config");
+					RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+				},
+				SIGTERM | SIGINT => {
+					println!("[main] Received stop signal");
+					for sink in signal_sinks.iter() {
+						sink.flush().await;
+					}
+					sync_cursor();
+					heartbeat::sync();
+					std::process::exit(0);
+				},
+				_ => {},
+			};
+		}
+	});
+
+	(j, sinks)
+}
+
+/// Entries routed to a destination no sink serves would be acknowledged and lost, so both
+/// that and two sinks sharing a name are refused
+fn check_destinations(settings: &AppSettings, sinks: &[SinkHandle]) -> Result<(), String> {
+	for (index, sink) in sinks.iter().enumerate() {
+		if !spool::is_valid_name(sink.name()) {
+			return Err(format!("The destination name '{}' can't contain '/' or '..'", sink.name()))
+		}
+		if sinks[..index].iter().any(|other| other.name() == sink.name()) {
+			return Err(format!("More than one destination is named '{}'", sink.name()))
+		}
+	}
+
+	let unknown: Vec<String> = routed_destinations(settings).into_iter()
+		.filter(|destination| !sinks.iter().any(|sink| sink.name() == destination))
+		.map(|destination| format!("'{}'", destination))
+		.collect();
+	match unknown.is_empty() {
+		true => Ok(()),
+		false => Err(format!("Rules route to {}, but no such destination is configured", unknown.join(", "))),
+	}
+}
+
+/// Re-read the config and swap in its rules, keeping the current ones if anything is invalid.
+/// Sink, journal and mute settings only take effect on restart
+fn reload(config_path: &str, settings: &mut AppSettings, j: &mut SysJournal, sinks: &[SinkHandle]) {
+	let new_settings = match read_config(config_path) {
+		Ok(new_settings) => new_settings,
+		Err(e) => {
+			println!("[reload] Error reading config, keeping the current one: {}", e);
+			return
 		}
+	};
+
+	if let Err(e) = check_destinations(&new_settings, sinks) {
+		println!("[reload] Invalid rules, keeping the current ones: {}", e);
+		return
+	}
+
+	// formats only take effect on restart, but one that would stop the next start is refused now
+	if let Err(e) = Formats::new(&new_settings.format) {
+		println!("[reload] Invalid format, keeping the current config: {}", e);
+		return
+	}
+
+	// checked before anything is swapped in, so a bad heartbeat leaves the rules alone too
+	if let Err(e) = heartbeat::validate(&new_settings) {
+		println!("[reload] Invalid heartbeats, keeping the current rules and heartbeats: {}", e);
+		return
+	}
+
+	match filter::reload(&new_settings, settings, j) {
+		Ok(_) => {
+			if let Err(e) = heartbeat::load(&new_settings) {
+				println!("[reload] Heartbeats not reloaded: {}", e);
+			}
+			*settings = new_settings;
+			println!("[reload] Rules reloaded from {}", config_path);
+		},
+		Err(e) => println!("[reload] Invalid rules, keeping the current ones: {}", e),
 	}
 }
 
-fn init(settings: AppSettings) -> (SysJournal, mpsc::Sender<LogEntry>) {
-	let mut j = open_journal_tail();
-	filter::init(&settings, &mut j);
-	let (tx, rx) = tokio::sync::mpsc::channel::<LogEntry>(40);
-	telegram::init(settings, rx);
-	(j, tx)
+fn modified_time(path: &Path) -> Option<SystemTime> {
+	std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
 }
 
 #[tokio::main]
@@ -61,30 +244,76 @@ async fn main() {
 
 	let args = parse_cli_args();
 
-	println!("[telelog] Starting telelog v0.2.1");
-
 	let config_path = match args.get_one::<PathBuf>("config") {
 		Some(path) => path.to_str().unwrap(),
-		None => {
-			println!("[main] Config file not specified, using '/etc/telelog.toml'");
-			"/etc/telelog.toml"
-		},
+		None => "/etc/telelog.toml",
 	};
 
-	let settings = match read_config(config_path) {
+	match args.subcommand() {
+		Some(("check", _)) => std::process::exit(check::run(config_path)),
+		Some(("test", test_args)) => std::process::exit(explain::run(config_path, test_args)),
+		_ => {},
+	}
+
+	if let Some((command, sub_args)) = args.subcommand() {
+		let socket = sub_args.get_one::<PathBuf>("socket").cloned()
+			.unwrap_or_else(|| config::control_socket(config_path));
+		let line = match command {
+			"mute" => format!("mute {} {}", sub_args.get_one::<String>("RULE").unwrap(), sub_args.get_one::<String>("DURATION").unwrap()),
+			"unmute" => format!("unmute {}", sub_args.get_one::<String>("RULE").map(|rule| rule.as_str()).unwrap_or("")),
+			_ => command.to_string(),
+		};
+		match mute::send_command(&socket, &line).await {
+			Ok(reply) => println!("{}", reply),
+			Err(e) => {
+				eprintln!("{}", e);
+				std::process::exit(1);
+			}
+		}
+		return;
+	}
+
+	println!("[telelog] Starting telelog v0.2.1");
+
+	if args.get_one::<PathBuf>("config").is_none() {
+		println!("[main] Config file not specified, using '/etc/telelog.toml'");
+	}
+
+	let mut settings = match read_config(config_path) {
 		Ok(settings) => settings,
 		Err(e) => {
 			println!("[main] Error reading config: {}", e);
 			return;
 		}
 	};
+	apply_journal_args(&mut settings.journal, &args);
+
+	let (mut j, sinks) = init(&settings);
+
+	// catch up on anything logged since the saved cursor before waiting for new entries
+	process_batch(&mut j, &sinks).await;
 
-	let (mut j, tx) = init(settings);
+	let watch_config = args.get_flag("watch");
+	let mut config_modified = modified_time(Path::new(config_path));
 
 	loop {
-		match j.wait(None) {
-			Ok(_) => process_batch(&mut j, &tx).await,
+		// wake up periodically so reload requests are picked up without waiting for a new entry
+		match j.wait(Some(Duration::from_secs(1))) {
+			Ok(_) => process_batch(&mut j, &sinks).await,
 			Err(_) => println!("[main] Timeout"),
 		}
+
+		if watch_config {
+			let modified = modified_time(Path::new(config_path));
+			if modified != config_modified {
+				config_modified = modified;
+				println!("[main] Config file changed, reloading");
+				RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+			}
+		}
+
+		if RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+			reload(config_path, &mut settings, &mut j, &sinks);
+		}
 	}
 }
@@ -0,0 +1,301 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use reqwest::Url;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::sleep;
+
+use crate::config::{MatrixSettings};
+use crate::helpers::{batch_lines, collapse_repeats, escape_html, is_permanent_failure, pack_lines, MAX_PAUSE};
+use crate::journal::LogEntry;
+use crate::sink::{Delivery, Sink};
+use crate::template::{Formats, LineFormat};
+
+/// Name of the destination built from the top level `matrix.room_id`
+const DEFAULT_DESTINATION: &str = "matrix";
+
+/// Longest message text, in UTF-16 code units. Events are capped at 64KiB of JSON,
+/// this leaves room for the body and its HTML twin however the text escapes
+const MESSAGE_LIMIT: usize = 4000;
+
+/// Counts the messages built by this process, together with the time they make transaction IDs unique
+static TRANSACTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// A message with the transaction ID it is sent under. The ID stays the same across retries,
+/// so the homeserver can tell a repeated send from a new message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MatrixMessage {
+	pub txn_id: String,
+	pub body: String,
+	pub formatted_body: String,
+}
+
+impl MatrixMessage {
+	fn new(body: String, formatted_body: String) -> Self {
+		let now = std::time::SystemTime::now()
+			.duration_since(std::time::UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_micros();
+		MatrixMessage {
+			txn_id: format!("telelog.{}.{}", now, TRANSACTIONS.fetch_add(1, Ordering::SeqCst)),
+			body,
+			formatted_body,
+		}
+	}
+}
+
+/// Posts batches to a Matrix room through the client-server API
+#[derive(Debug)]
+pub struct MatrixSink {
+	name: String,
+	homeserver: String,
+	access_token: String,
+	room_id: String,
+	flush_seconds: u16,
+	dedup_window: Option<Duration>,
+	line_format: LineFormat,
+	http: reqwest::Client,
+	send_lock: Arc<AsyncMutex<()>>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+	errcode: Option<String>,
+	error: Option<String>,
+	retry_after_ms: Option<u64>,
+}
+
+/// Names rule groups can route to: the default room, if set, and every named destination
+pub fn destination_names(settings: &MatrixSettings) -> Vec<String> {
+	let mut names: Vec<String> = settings.room_id.iter().map(|_| DEFAULT_DESTINATION.to_string()).collect();
+	names.extend(settings.destinations.keys().cloned());
+	names
+}
+
+/// Build a sink for the default room and one for each named destination, nothing if no homeserver is set.
+/// They share one send lock, as homeservers rate limit per account rather than per room
+pub fn sinks(settings: &MatrixSettings, formats: &Formats) -> Vec<MatrixSink> {
+	let homeserver = match &settings.homeserver {
+		Some(homeserver) => homeserver.trim_end_matches('/').to_string(),
+		None => return Vec::new(),
+	};
+	let http = reqwest::Client::new();
+	let dedup_window = formats.dedup_window;
+	let send_lock = Arc::new(AsyncMutex::new(()));
+
+	let mut rooms: Vec<(String, String)> = settings.room_id.iter().map(|room_id| (DEFAULT_DESTINATION.to_string(), room_id.clone())).collect();
+	rooms.extend(settings.destinations.iter().map(|(name, destination)| (name.clone(), destination.room_id.clone())));
+
+	rooms.into_iter().map(|(name, room_id)| {
+		let line_format = formats.line_format(&name);
+
+		MatrixSink {
+			name,
+			homeserver: homeserver.clone(),
+			access_token: settings.access_token.clone().unwrap_or_default(),
+			room_id,
+			flush_seconds: settings.flush_seconds.unwrap_or(5),
+			dedup_window,
+			line_format,
+			http: http.clone(),
+			send_lock: send_lock.clone(),
+		}
+	}).collect()
+}
+
+impl MatrixSink {
+	/// `PUT /rooms/{roomId}/send/m.room.message/{txnId}`, with every segment percent-encoded
+	fn send_url(&self, txn_id: &str) -> Result<Url, String> {
+		let mut url = Url::parse(&self.homeserver).map_err(|e| format!("invalid homeserver '{}': {}", self.homeserver, e))?;
+		url.path_segments_mut()
+			.map_err(|_| format!("invalid homeserver '{}'", self.homeserver))?
+			.pop_if_empty()
+			.extend(["_matrix", "client", "v3", "rooms", &self.room_id, "send", "m.room.message", txn_id]);
+		Ok(url)
+	}
+
+	async fn send(&self, url: Url, message: &MatrixMessage) -> Result<reqwest::Response, reqwest::Error> {
+		let _guard = self.send_lock.clone().lock_owned().await;
+		let content = json!({
+			"msgtype": "m.text",
+			"body": message.body,
+			"format": "org.matrix.custom.html",
+			"formatted_body": message.formatted_body,
+		});
+		self.http.put(url)
+			.bearer_auth(&self.access_token)
+			.header(reqwest::header::CONTENT_TYPE, "application/json")
+			.body(content.to_string())
+			.send()
+			.await
+	}
+}
+
+impl Sink for MatrixSink {
+	type Message = MatrixMessage;
+
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn is_default(&self) -> bool {
+		self.name == DEFAULT_DESTINATION
+	}
+
+	fn flush_seconds(&self) -> u32 {
+		self.flush_seconds.into()
+	}
+
+	fn format(&self, entries: &[LogEntry]) -> Vec<MatrixMessage> {
+		let lines = batch_lines(&collapse_repeats(entries, self.dedup_window), &self.line_format);
+		pack_lines(&lines, MESSAGE_LIMIT).into_iter().map(|lines| {
+			let escaped: Vec<String> = lines.iter().map(|line| escape_html(line)).collect();
+			MatrixMessage::new(lines.join("\n"), format!("<pre><code>{}\n</code></pre>", escaped.join("\n")))
+		}).collect()
+	}
+
+	// messages are never combined: one that was delivered but not acknowledged would be
+	// deduplicated by its transaction ID, taking whatever it was combined with along
+
+	fn notice(&self, text: &str) -> MatrixMessage {
+		let text = format!("⚠️ {}", text);
+		MatrixMessage::new(text.clone(), escape_html(&text))
+	}
+
+	async fn deliver(&self, message: &MatrixMessage) -> Delivery<MatrixMessage> {
+		let url = match self.send_url(&message.txn_id) {
+			Ok(url) => url,
+			Err(e) => {
+				eprintln!("[matrix] {}", e);
+				return Delivery::Retry(message.clone())
+			}
+		};
+
+		let response = match self.send(url, message).await {
+			Ok(response) => response,
+			Err(e) => {
+				eprintln!("[matrix] Failed: {}", e);
+				return Delivery::Retry(message.clone())
+			}
+		};
+
+		if response.status().is_success() {
+			return Delivery::Sent
+		}
+
+		let status = response.status();
+		let retry_after_header = response.headers().get("retry-after")
+			.and_then(|value| value.to_str().ok())
+			.and_then(|value| value.parse::<u64>().ok())
+			.map(Duration::from_secs);
+		let text = response.text().await.unwrap_or_default();
+		let error: Option<ErrorResponse> = serde_json::from_str(&text).ok();
+
+		match error {
+			Some(ErrorResponse { errcode: Some(errcode), retry_after_ms, .. }) if errcode == "M_LIMIT_EXCEEDED" => {
+				let retry_after = retry_after_ms.map(Duration::from_millis).or(retry_after_header).unwrap_or(Duration::from_secs(self.flush_seconds as u64)).min(MAX_PAUSE);
+				eprintln!("[matrix] M_LIMIT_EXCEEDED: pausing messages for {:.1} seconds", retry_after.as_secs_f64());
+				// take the lock before returning, so the next send is queued behind the pause
+				let _guard = self.send_lock.clone().lock_owned().await;
+				tokio::spawn(async move {
+					sleep(retry_after).await;
+					drop(_guard);
+				});
+			},
+			Some(ErrorResponse { errcode: Some(errcode), error, .. }) => {
+				let error = error.unwrap_or_default();
+				println!("[matrix] API response {}: {} {}", status, errcode, error);
+				if is_permanent_failure(status) {
+					return Delivery::Drop(format!("{} {} {}", status, errcode, error))
+				}
+			},
+			_ if is_permanent_failure(status) => {
+				println!("[matrix] API response {}: {:?}", status, text);
+				return Delivery::Drop(format!("{} {}", status, text))
+			},
+			_ => println!("[matrix] API response {}: {:?}", status, text),
+		}
+
+		Delivery::Retry(message.clone())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+	use tokio::time::Instant;
+
+	use chrono::Local;
+
+	use super::*;
+	use crate::config::SpoolSettings;
+	use crate::mock_http::MockServer;
+	use crate::sink;
+
+	fn settings(homeserver: &str) -> MatrixSettings {
+		MatrixSettings {
+			homeserver: Some(format!("{}/", homeserver)),
+			access_token: Some("syt_token".to_string()),
+			room_id: Some("!room:example.org".to_string()),
+			flush_seconds: Some(1),
+			destinations: Default::default(),
+		}
+	}
+
+	fn entry(message: &str) -> LogEntry {
+		LogEntry::new(3, Local::now(), "test".to_string(), message.to_string(), BTreeMap::new())
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn sends_html_and_plain_text() {
+		let server = MockServer::start(vec![(200, Vec::new(), r#"{"event_id":"$1"}"#.to_string())]).await;
+		let handle = sink::spawn(sinks(&settings(&server.url), &Formats::default()).remove(0), &SpoolSettings::default());
+
+		handle.send(entry("first")).await;
+		handle.send(entry("<b>not bold</b> & more")).await;
+		server.wait_for(1).await;
+
+		let requests = server.requests();
+		assert_eq!(requests.len(), 1);
+		assert!(requests[0].path.starts_with("/_matrix/client/v3/rooms/!room:example.org/send/m.room.message/telelog."));
+		assert_eq!(requests[0].headers["authorization"], "Bearer syt_token");
+
+		let content = requests[0].json();
+		assert_eq!(content["msgtype"], "m.text");
+		assert_eq!(content["format"], "org.matrix.custom.html");
+		let body = content["body"].as_str().unwrap();
+		assert!(body.contains("test: first\n") && body.ends_with("test: <b>not bold</b> & more"));
+		let formatted_body = content["formatted_body"].as_str().unwrap();
+		assert!(formatted_body.starts_with("<pre><code>") && formatted_body.ends_with("\n</code></pre>"));
+		assert!(formatted_body.contains("test: &lt;b&gt;not bold&lt;/b&gt; &amp; more\n"));
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn retries_with_the_same_transaction_after_rate_limit() {
+		let server = MockServer::start(vec![
+			(429, Vec::new(), r#"{"errcode":"M_LIMIT_EXCEEDED","error":"Too many requests","retry_after_ms":1500}"#.to_string()),
+		]).await;
+		let matrix = sinks(&settings(&server.url), &Formats::default()).remove(0);
+		let first = matrix.format(&[entry("rate limited")]).remove(0);
+		let second = matrix.format(&[entry("rate limited")]).remove(0);
+		assert_ne!(first.txn_id, second.txn_id);
+
+		let started = Instant::now();
+		let retry = match matrix.deliver(&first).await {
+			Delivery::Retry(retry) => retry,
+			Delivery::Sent => panic!("M_LIMIT_EXCEEDED reported as sent"),
+			Delivery::Drop(reason) => panic!("M_LIMIT_EXCEEDED dropped: {}", reason),
+		};
+		assert_eq!(retry, first);
+		assert!(matches!(matrix.deliver(&retry).await, Delivery::Sent));
+
+		let requests = server.requests();
+		assert_eq!(requests.len(), 2);
+		assert_eq!(requests[0].path, requests[1].path);
+		assert!(requests[1].path.ends_with(&first.txn_id));
+		assert!(requests[1].received.duration_since(started) >= Duration::from_millis(1500));
+	}
+}
@@ -0,0 +1,110 @@
+//! Minimal HTTP server for testing sinks end to end. Replies to each request with the next
+//! queued response, or `200 {"ok":true}` once they run out, and records what it received.
+//! Timestamps follow tokio's clock, so tests can pause it and wait for requests instead of sleeping
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::time::{timeout, Instant};
+
+/// How long `wait_for` waits before failing the test, on tokio's clock
+pub const WAIT_LIMIT: Duration = Duration::from_secs(60);
+
+pub struct Request {
+	pub path: String,
+	pub headers: HashMap<String, String>,
+	pub body: Vec<u8>,
+	pub received: Instant,
+}
+
+impl Request {
+	/// Decode an `application/x-www-form-urlencoded` body
+	pub fn form(&self) -> HashMap<String, String> {
+		serde_urlencoded::from_bytes(&self.body).expect("request body is not a form")
+	}
+
+	pub fn json(&self) -> serde_json::Value {
+		serde_json::from_slice(&self.body).expect("request body is not JSON")
+	}
+}
+
+/// A canned reply: status, extra headers and a body
+pub type Response = (u16, Vec<(&'static str, String)>, String);
+
+pub struct MockServer {
+	pub url: String,
+	requests: Arc<Mutex<Vec<Request>>>,
+	received: watch::Receiver<usize>,
+}
+
+impl MockServer {
+	/// Start serving on a random local port, replying with `responses` in order
+	pub async fn start(responses: Vec<Response>) -> Self {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}", listener.local_addr().unwrap());
+		let requests: Arc<Mutex<Vec<Request>>> = Arc::new(Mutex::new(Vec::new()));
+		let responses = Arc::new(Mutex::new(responses.into_iter()));
+		let (count, received) = watch::channel(0);
+
+		let recorded = requests.clone();
+		tokio::spawn(async move {
+			loop {
+				let (stream, _) = listener.accept().await.unwrap();
+				let (reader, mut writer) = stream.into_split();
+				let mut reader = BufReader::new(reader);
+
+				let mut request_line = String::new();
+				reader.read_line(&mut request_line).await.unwrap();
+				let path = request_line.split_whitespace().nth(1).unwrap_or_default().to_string();
+
+				let mut headers = HashMap::new();
+				loop {
+					let mut line = String::new();
+					reader.read_line(&mut line).await.unwrap();
+					let line = line.trim_end();
+					if line.is_empty() {
+						break
+					}
+					if let Some((name, value)) = line.split_once(':') {
+						headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+					}
+				}
+
+				let length = headers.get("content-length").and_then(|length| length.parse().ok()).unwrap_or(0);
+				let mut body = vec![0; length];
+				reader.read_exact(&mut body).await.unwrap();
+
+				recorded.lock().unwrap().push(Request { path, headers, body, received: Instant::now() });
+				count.send_modify(|count| *count += 1);
+
+				let (status, extra_headers, body) = responses.lock().unwrap().next()
+					.unwrap_or((200, Vec::new(), "{\"ok\":true}".to_string()));
+				let mut response = format!("HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n", status, body.len());
+				for (name, value) in extra_headers {
+					response.push_str(&format!("{}: {}\r\n", name, value));
+				}
+				response.push_str("\r\n");
+				response.push_str(&body);
+				let _ = writer.write_all(response.as_bytes()).await;
+			}
+		});
+
+		MockServer { url, requests, received }
+	}
+
+	/// Wait until at least `count` requests have arrived, failing the test if they don't
+	pub async fn wait_for(&self, count: usize) {
+		let mut received = self.received.clone();
+		if timeout(WAIT_LIMIT, received.wait_for(|received| *received >= count)).await.is_err() {
+			panic!("expected {} requests, got {}", count, self.requests().len());
+		}
+	}
+
+	pub fn requests(&self) -> std::sync::MutexGuard<'_, Vec<Request>> {
+		self.requests.lock().unwrap()
+	}
+}
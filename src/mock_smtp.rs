@@ -0,0 +1,137 @@
+//! Minimal SMTP capture server for testing the email sink end to end. Accepts any sender,
+//! recipient and credentials without TLS, and records each message it is given
+
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::time::timeout;
+
+use crate::mock_http::WAIT_LIMIT;
+
+pub struct Mail {
+	/// Arguments of the AUTH command, e.g. `PLAIN <base64>`
+	pub auth: Option<String>,
+	pub from: String,
+	pub to: Vec<String>,
+	/// Headers and body, with dot-stuffing undone
+	pub data: String,
+}
+
+pub struct MockSmtpServer {
+	pub port: u16,
+	mails: Arc<Mutex<Vec<Mail>>>,
+	received: watch::Receiver<usize>,
+}
+
+/// The address between the angle brackets of `MAIL FROM:<...>` or `RCPT TO:<...>`
+fn address(argument: &str) -> String {
+	argument.split_once('<')
+		.and_then(|(_, rest)| rest.split_once('>'))
+		.map(|(address, _)| address.to_string())
+		.unwrap_or_default()
+}
+
+impl MockSmtpServer {
+	/// Start serving on a random local port. The first `refusals` messages are refused with a
+	/// temporary error after their DATA is sent
+	pub async fn start(refusals: usize) -> Self {
+		Self::start_refusing(refusals, "451 4.3.0 Try again later").await
+	}
+
+	/// Like `start`, refusing with the given reply instead
+	pub async fn start_refusing(refusals: usize, reply: &'static str) -> Self {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let mails: Arc<Mutex<Vec<Mail>>> = Arc::new(Mutex::new(Vec::new()));
+		let refusals = Arc::new(Mutex::new(refusals));
+		let (count, received) = watch::channel(0);
+		let count = Arc::new(count);
+
+		let recorded = mails.clone();
+		tokio::spawn(async move {
+			loop {
+				let (stream, _) = listener.accept().await.unwrap();
+				let recorded = recorded.clone();
+				let refusals = refusals.clone();
+				let count = count.clone();
+				tokio::spawn(async move {
+					let (reader, mut writer) = stream.into_split();
+					let mut reader = BufReader::new(reader);
+					let mut mail = Mail { auth: None, from: String::new(), to: Vec::new(), data: String::new() };
+					writer.write_all(b"220 mock ESMTP\r\n").await.unwrap();
+
+					loop {
+						let mut line = String::new();
+						if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+							break
+						}
+						let line = line.trim_end();
+						let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+						let reply = match command.to_ascii_uppercase().as_str() {
+							"EHLO" => "250-mock\r\n250 AUTH PLAIN LOGIN\r\n".to_string(),
+							"AUTH" => {
+								mail.auth = Some(argument.to_string());
+								"235 2.7.0 Authentication successful\r\n".to_string()
+							},
+							"MAIL" => {
+								mail.from = address(argument);
+								"250 2.1.0 Ok\r\n".to_string()
+							},
+							"RCPT" => {
+								mail.to.push(address(argument));
+								"250 2.1.5 Ok\r\n".to_string()
+							},
+							"DATA" => {
+								writer.write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n").await.unwrap();
+								loop {
+									let mut line = String::new();
+									reader.read_line(&mut line).await.unwrap();
+									if line == ".\r\n" {
+										break
+									}
+									mail.data.push_str(line.strip_prefix('.').unwrap_or(&line));
+								}
+
+								let mut refusals = refusals.lock().unwrap();
+								if *refusals > 0 {
+									*refusals -= 1;
+									mail.data.clear();
+									format!("{}\r\n", reply)
+								} else {
+									let auth = mail.auth.clone();
+									let received = std::mem::replace(&mut mail, Mail { auth, from: String::new(), to: Vec::new(), data: String::new() });
+									recorded.lock().unwrap().push(received);
+									count.send_modify(|count| *count += 1);
+									"250 2.0.0 Ok: queued\r\n".to_string()
+								}
+							},
+							"RSET" | "NOOP" => "250 2.0.0 Ok\r\n".to_string(),
+							"QUIT" => {
+								let _ = writer.write_all(b"221 2.0.0 Bye\r\n").await;
+								break
+							},
+							_ => "502 5.5.2 Command not recognized\r\n".to_string(),
+						};
+						writer.write_all(reply.as_bytes()).await.unwrap();
+					}
+				});
+			}
+		});
+
+		MockSmtpServer { port, mails, received }
+	}
+
+	/// Wait until at least `count` mails have been accepted, failing the test if they aren't
+	pub async fn wait_for(&self, count: usize) {
+		let mut received = self.received.clone();
+		if timeout(WAIT_LIMIT, received.wait_for(|received| *received >= count)).await.is_err() {
+			panic!("expected {} mails, got {}", count, self.mails().len());
+		}
+	}
+
+	pub fn mails(&self) -> std::sync::MutexGuard<'_, Vec<Mail>> {
+		self.mails.lock().unwrap()
+	}
+}
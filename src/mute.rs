@@ -0,0 +1,366 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use chrono::{DateTime, Local, TimeZone};
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::config::MuteSettings;
+use crate::helpers::parse_duration;
+use crate::journal::LogEntry;
+
+static MUTES: Mutex<Vec<Mute>> = Mutex::new(Vec::new());
+static STATE_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+const DEFAULT_FIELD: &str = "SYSLOG_IDENTIFIER";
+
+/// How a mute compares the field value, exactly unless the rule starts with `~`
+#[derive(Debug, Clone)]
+pub enum MuteValue {
+	Exact(String),
+	Pattern { pattern: String, re: Regex },
+}
+
+impl MuteValue {
+	/// Parse a value as written in commands, `~regex` is matched against the whole field
+	fn parse(value: &str) -> Result<MuteValue, String> {
+		match value.strip_prefix('~') {
+			Some(pattern) => Regex::new(&format!("^(?:{})$", pattern))
+				.map(|re| MuteValue::Pattern { pattern: pattern.to_string(), re })
+				.map_err(|e| format!("Invalid regex '{}': {}", pattern, e)),
+			None => Ok(MuteValue::Exact(value.to_string())),
+		}
+	}
+
+	fn is_match(&self, value: &str) -> bool {
+		match self {
+			MuteValue::Exact(expected) => expected == value,
+			MuteValue::Pattern { re, .. } => re.is_match(value),
+		}
+	}
+
+	/// The value as written in commands
+	fn as_written(&self) -> String {
+		match self {
+			MuteValue::Exact(value) => value.clone(),
+			MuteValue::Pattern { pattern, .. } => format!("~{}", pattern),
+		}
+	}
+}
+
+/// A temporary deny rule: entries whose field matches the value are ignored until it expires
+#[derive(Debug, Clone)]
+pub struct Mute {
+	pub field: String,
+	pub value: MuteValue,
+	pub until: DateTime<Local>,
+}
+
+impl Mute {
+	/// The rule as written in commands, `FIELD=value` or just the value for identifiers
+	pub fn key(&self) -> String {
+		mute_key(&self.field, &self.value.as_written())
+	}
+}
+
+/// How mutes are persisted in the state file
+#[derive(Serialize, Deserialize)]
+struct SavedMute {
+	field: String,
+	value: String,
+	until: i64,
+}
+
+fn mute_key(field: &str, value: &str) -> String {
+	if field == DEFAULT_FIELD {
+		value.to_string()
+	} else {
+		format!("{}={}", field, value)
+	}
+}
+
+/// Split `FIELD=value` into its parts, a bare value is matched against the identifier
+fn parse_key(key: &str) -> (String, String) {
+	match key.split_once('=') {
+		Some((field, value)) if !field.is_empty() && field.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_') => {
+			(field.to_string(), value.to_string())
+		},
+		_ => (DEFAULT_FIELD.to_string(), key.to_string()),
+	}
+}
+
+/// Load persisted mutes, dropping any that expired while telelog was not running
+pub fn init(settings: &MuteSettings) {
+	let state_file = match &settings.state_file {
+		Some(path) => path,
+		None => return,
+	};
+	STATE_FILE.set(state_file.clone()).expect("Initialisation only occurs once");
+
+	let saved: Vec<SavedMute> = match std::fs::read_to_string(state_file) {
+		Ok(text) => match serde_json::from_str(&text) {
+			Ok(saved) => saved,
+			Err(e) => {
+				eprintln!("[mute] Failed to parse {}: {}", state_file.display(), e);
+				return
+			}
+		},
+		Err(e) => {
+			if e.kind() != std::io::ErrorKind::NotFound {
+				eprintln!("[mute] Failed to read {}: {}", state_file.display(), e);
+			}
+			return
+		}
+	};
+
+	let now = Local::now();
+	let mut mutes = MUTES.lock().unwrap();
+	for saved_mute in saved {
+		let until = match Local.timestamp_opt(saved_mute.until, 0).single() {
+			Some(until) if until > now => until,
+			_ => continue,
+		};
+		match MuteValue::parse(&saved_mute.value) {
+			Ok(value) => mutes.push(Mute { field: saved_mute.field, value, until }),
+			Err(e) => eprintln!("[mute] Skipping saved mute '{}': {}", saved_mute.value, e),
+		}
+	}
+	println!("[mute] Loaded {} active mutes", mutes.len());
+}
+
+fn save(mutes: &[Mute]) {
+	let path = match STATE_FILE.get() {
+		Some(path) => path,
+		None => return,
+	};
+
+	let saved: Vec<SavedMute> = mutes.iter().map(|mute| SavedMute {
+		field: mute.field.clone(),
+		value: mute.value.as_written(),
+		until: mute.until.timestamp(),
+	}).collect();
+
+	if let Some(parent) = path.parent() {
+		if let Err(e) = std::fs::create_dir_all(parent) {
+			eprintln!("[mute] Failed to create state directory {}: {}", parent.display(), e);
+			return
+		}
+	}
+
+	let tmp_path = path.with_extension("tmp");
+	let result = std::fs::write(&tmp_path, serde_json::to_string(&saved).unwrap())
+		.and_then(|_| std::fs::rename(&tmp_path, path));
+
+	if let Err(e) = result {
+		eprintln!("[mute] Failed to save mutes to {}: {}", path.display(), e);
+	}
+}
+
+/// Mute entries matching `key` for a duration, replacing any existing mute with the same key.
+/// Returns the expiry time
+pub fn mute(key: &str, duration: std::time::Duration) -> Result<DateTime<Local>, String> {
+	let (field, written) = parse_key(key);
+	let value = MuteValue::parse(&written)?;
+	let until = chrono::Duration::from_std(duration).ok()
+		.and_then(|duration| Local::now().checked_add_signed(duration))
+		.ok_or_else(|| format!("Duration '{:?}' is out of range", duration))?;
+
+	let mut mutes = MUTES.lock().unwrap();
+	let key = mute_key(&field, &written);
+	mutes.retain(|mute| mute.key() != key);
+	mutes.push(Mute { field, value, until });
+	save(&mutes);
+	Ok(until)
+}
+
+/// Remove the mute with this key, or every mute if none is given. Returns how many were removed
+pub fn unmute(key: Option<&str>) -> usize {
+	let mut mutes = MUTES.lock().unwrap();
+	let before = mutes.len();
+	match key {
+		Some(key) => {
+			let (field, value) = parse_key(key);
+			let key = mute_key(&field, &value);
+			mutes.retain(|mute| mute.key() != key)
+		},
+		None => mutes.clear(),
+	}
+	let removed = before - mutes.len();
+	if removed > 0 {
+		save(&mutes);
+	}
+	removed
+}
+
+/// Currently active mutes, dropping any that have expired
+pub fn active() -> Vec<Mute> {
+	let mut mutes = MUTES.lock().unwrap();
+	let now = Local::now();
+	let before = mutes.len();
+	mutes.retain(|mute| mute.until > now);
+	if mutes.len() != before {
+		save(&mutes);
+	}
+	mutes.clone()
+}
+
+/// Returns true if the entry is muted and should be ignored
+pub fn is_muted(entry: &LogEntry) -> bool {
+	let mutes = MUTES.lock().unwrap();
+	if mutes.is_empty() {
+		return false
+	}
+	let now = Local::now();
+	mutes.iter().any(|mute| {
+		mute.until > now && match entry.get_field(&mute.field) {
+			Ok(value) => mute.value.is_match(&value),
+			Err(_) => false,
+		}
+	})
+}
+
+/// Run a mute command shared by the bot, the control socket and the CLI.
+/// `command` is one of `mute`, `unmute` or `mutes`, returns the plain text reply
+pub fn run_command(command: &str, args: &[&str]) -> Result<String, String> {
+	match (command, args) {
+		("mute", [key, duration]) => {
+			let duration = parse_duration(duration).ok_or_else(|| format!("Invalid duration '{}', use e.g. 30m, 2h or 1d", duration))?;
+			let until = mute(key, duration)?;
+			println!("[mute] Muted {} until {}", key, until);
+			Ok(format!("Muted {} until {}", key, until.format("%b %d %H:%M:%S")))
+		},
+		("mute", _) => Err("Usage: mute <identifier|FIELD=value|[FIELD=]~regex> <duration>".to_string()),
+		("unmute", [key]) => match unmute(Some(key)) {
+			0 => Err(format!("{} was not muted", key)),
+			_ => {
+				println!("[mute] Unmuted {}", key);
+				Ok(format!("Unmuted {}", key))
+			},
+		},
+		("unmute", []) => {
+			let removed = unmute(None);
+			println!("[mute] Removed {} mutes", removed);
+			Ok(format!("Removed {} mutes", removed))
+		},
+		("unmute", _) => Err("Usage: unmute [identifier|FIELD=value|[FIELD=]~regex]".to_string()),
+		("mutes", _) => {
+			let mutes = active();
+			if mutes.is_empty() {
+				return Ok("No active mutes".to_string())
+			}
+			Ok(mutes.iter()
+				.map(|mute| format!("{} until {}", mute.key(), mute.until.format("%b %d %H:%M:%S")))
+				.collect::<Vec<_>>()
+				.join("\n"))
+		},
+		_ => Err(format!("Unknown command '{}'", command)),
+	}
+}
+
+/// Listen on the local control socket for mute commands, one command per connection
+pub fn spawn_control_socket(settings: &MuteSettings) {
+	let path = match &settings.control_socket {
+		Some(path) => path.clone(),
+		None => return,
+	};
+
+	if let Some(parent) = path.parent() {
+		let _ = std::fs::create_dir_all(parent);
+	}
+	// a socket left behind by a previous run would make the bind fail
+	let _ = std::fs::remove_file(&path);
+
+	// create the socket owner-only from the start, chmod after bind would leave a window
+	// SAFETY: umask only swaps the process file mode mask and cannot fail
+	let previous_umask = unsafe { libc::umask(0o177) };
+	let bound = UnixListener::bind(&path);
+	unsafe { libc::umask(previous_umask) };
+
+	let listener = match bound {
+		Ok(listener) => listener,
+		Err(e) => {
+			eprintln!("[mute] Failed to bind control socket {}: {}", path.display(), e);
+			return
+		}
+	};
+
+	tokio::spawn(async move {
+		loop {
+			match listener.accept().await {
+				Ok((stream, _)) => {
+					tokio::spawn(handle_connection(stream));
+				},
+				Err(e) => eprintln!("[mute] Control socket error: {}", e),
+			}
+		}
+	});
+
+	println!("[mute] Control socket listening on {}", path.display());
+}
+
+async fn handle_connection(stream: UnixStream) {
+	let (reader, mut writer) = stream.into_split();
+	let mut line = String::new();
+	if BufReader::new(reader).read_line(&mut line).await.is_err() {
+		return
+	}
+
+	let mut words = line.split_whitespace();
+	let command = words.next().unwrap_or("");
+	let args: Vec<&str> = words.collect();
+
+	let reply = match run_command(command, &args) {
+		Ok(reply) => format!("ok\n{}\n", reply),
+		Err(e) => format!("error\n{}\n", e),
+	};
+	let _ = writer.write_all(reply.as_bytes()).await;
+}
+
+/// Send a command to a running telelog over its control socket, returning its reply
+pub async fn send_command(socket: &Path, command: &str) -> Result<String, String> {
+	let mut stream = UnixStream::connect(socket).await
+		.map_err(|e| format!("Could not connect to {}: {}", socket.display(), e))?;
+	stream.write_all(format!("{}\n", command).as_bytes()).await.map_err(|e| e.to_string())?;
+
+	let mut reply = String::new();
+	let mut reader = BufReader::new(stream);
+	let mut status = String::new();
+	reader.read_line(&mut status).await.map_err(|e| e.to_string())?;
+	tokio::io::AsyncReadExt::read_to_string(&mut reader, &mut reply).await.map_err(|e| e.to_string())?;
+
+	match status.trim() {
+		"ok" => Ok(reply.trim_end().to_string()),
+		_ => Err(reply.trim_end().to_string()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn plain_values_match_exactly() {
+		let value = MuteValue::parse("cron").unwrap();
+		assert!(value.is_match("cron"));
+		assert!(!value.is_match("anacron"));
+		assert!(!value.is_match("cron.daily"));
+		assert_eq!(value.as_written(), "cron");
+	}
+
+	#[test]
+	fn patterns_match_the_whole_value() {
+		let value = MuteValue::parse("~cron|anacron").unwrap();
+		assert!(value.is_match("cron"));
+		assert!(value.is_match("anacron"));
+		assert!(!value.is_match("crond"));
+		assert_eq!(value.as_written(), "~cron|anacron");
+		assert!(MuteValue::parse("~(").is_err());
+	}
+
+	#[test]
+	fn out_of_range_durations_are_refused() {
+		assert!(mute("cron", std::time::Duration::from_secs(u64::MAX)).is_err());
+	}
+}
@@ -15,17 +15,18 @@ fn clean_message(message: &str) -> String {
 	RE.replace_all(message, "").trim_end_matches('\n').to_string()
 }
 
-pub fn parse_message(entry: sysjournal::JournalRecord) -> Option<LogEntry> {
+/// Turn a journal record into a log entry, or say which of its fields is malformed
+pub fn parse_message(entry: sysjournal::JournalRecord) -> Result<LogEntry, String> {
 	
 	let timestamp = match entry.get("_SOURCE_REALTIME_TIMESTAMP") {
 		Some(t) => {
-			let t = t.parse::<u64>().unwrap();
+			let t = t.parse::<u64>().map_err(|_| format!("invalid _SOURCE_REALTIME_TIMESTAMP '{}'", t))?;
 			let t = t / 1000000; // convert from ns to seconds
-			let t = t as i64;
+			let t = i64::try_from(t).map_err(|_| format!("_SOURCE_REALTIME_TIMESTAMP {} is out of range", t))?;
 			let t: DateTime<Local> = match Local.timestamp_opt(t, 0) {
 				LocalResult::Single(t) => t,
-				LocalResult::None => panic!("Invalid timestamp"),
-				LocalResult::Ambiguous(_, _) => panic!("Ambiguous timestamp"),
+				LocalResult::None => return Err(format!("_SOURCE_REALTIME_TIMESTAMP {} is out of range", t)),
+				LocalResult::Ambiguous(t, _) => t,
 			};
 			t
 		},
@@ -46,15 +47,140 @@ pub fn parse_message(entry: sysjournal::JournalRecord) -> Option<LogEntry> {
 	};
 
 	let priority = match entry.get("PRIORITY") {
-		Some(p) => p.parse::<u8>().unwrap(),
+		Some(p) => match p.parse::<u8>() {
+			Ok(p) if p < 8 => p,
+			_ => return Err(format!("invalid PRIORITY '{}', journald writes 0 to 7", p)),
+		},
 		None => 7,
 	};
 
-	return Some(LogEntry::new(
+	Ok(LogEntry::new(
 		priority,
 		timestamp,
 		identifier,
 		message,
 		entry,
-	));
+	))
+}
+
+/// Parse the output of `journalctl -o export` into records
+pub fn parse_export(data: &[u8]) -> Result<Vec<sysjournal::JournalRecord>, String> {
+	let mut records = Vec::new();
+	let mut record = sysjournal::JournalRecord::new();
+	let mut pos = 0;
+
+	while pos < data.len() {
+		let line_end = data[pos..].iter().position(|b| *b == b'\n').map(|i| pos + i).unwrap_or(data.len());
+		let line = &data[pos..line_end];
+		pos = line_end + 1;
+
+		// a blank line ends the entry
+		if line.is_empty() {
+			if !record.is_empty() {
+				records.push(std::mem::take(&mut record));
+			}
+			continue
+		}
+
+		match line.iter().position(|b| *b == b'=') {
+			Some(eq) => {
+				let field = String::from_utf8_lossy(&line[..eq]).to_string();
+				let value = String::from_utf8_lossy(&line[eq + 1..]).to_string();
+				record.insert(field, value);
+			},
+			None => {
+				// binary safe fields are the name on its own line, a little endian u64 length, the data and a newline
+				let field = String::from_utf8_lossy(line).to_string();
+				let length_bytes: [u8; 8] = data.get(pos..pos + 8)
+					.and_then(|bytes| bytes.try_into().ok())
+					.ok_or_else(|| format!("Truncated length for binary field {}", field))?;
+				let end = usize::try_from(u64::from_le_bytes(length_bytes)).ok()
+					.and_then(|length| (pos + 8).checked_add(length))
+					.ok_or_else(|| format!("Invalid length for binary field {}", field))?;
+				let value = data.get(pos + 8..end)
+					.ok_or_else(|| format!("Truncated data for binary field {}", field))?;
+				record.insert(field, String::from_utf8_lossy(value).to_string());
+				pos = end + 1;
+			},
+		}
+	}
+
+	if !record.is_empty() {
+		records.push(record);
+	}
+	Ok(records)
+}
+
+fn json_field_value(value: &serde_json::Value) -> Option<String> {
+	match value {
+		serde_json::Value::String(s) => Some(s.clone()),
+		serde_json::Value::Number(n) => Some(n.to_string()),
+		serde_json::Value::Bool(b) => Some(b.to_string()),
+		// journalctl writes fields that are not valid UTF-8 as arrays of bytes
+		serde_json::Value::Array(items) if items.iter().all(|item| item.is_u64()) => {
+			let bytes: Vec<u8> = items.iter().filter_map(|item| item.as_u64()).map(|b| b as u8).collect();
+			Some(String::from_utf8_lossy(&bytes).to_string())
+		},
+		// and fields with several values as arrays of values, of which we only keep the first
+		serde_json::Value::Array(items) => items.first().and_then(json_field_value),
+		_ => None,
+	}
+}
+
+fn json_record(value: &serde_json::Value) -> Result<sysjournal::JournalRecord, String> {
+	let object = value.as_object().ok_or_else(|| format!("Expected a JSON object of journal fields, got {}", value))?;
+	Ok(object.iter()
+		.filter_map(|(field, value)| json_field_value(value).map(|value| (field.clone(), value)))
+		.collect())
+}
+
+/// Parse journal entries given as JSON: one object per line as written by `journalctl -o json`,
+/// or a single array of objects
+pub fn parse_json(text: &str) -> Result<Vec<sysjournal::JournalRecord>, String> {
+	if text.trim_start().starts_with('[') {
+		let values: Vec<serde_json::Value> = serde_json::from_str(text).map_err(|e| e.to_string())?;
+		return values.iter().map(json_record).collect()
+	}
+
+	text.lines()
+		.filter(|line| !line.trim().is_empty())
+		.enumerate()
+		.map(|(index, line)| {
+			let value: serde_json::Value = serde_json::from_str(line).map_err(|e| format!("line {}: {}", index + 1, e))?;
+			json_record(&value)
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn record(fields: &[(&str, &str)]) -> sysjournal::JournalRecord {
+		fields.iter().map(|(field, value)| (field.to_string(), value.to_string())).collect()
+	}
+
+	#[test]
+	fn reports_malformed_fields() {
+		let entry = parse_message(record(&[("MESSAGE", "hello"), ("PRIORITY", "3"), ("_SOURCE_REALTIME_TIMESTAMP", "1700000000000000")])).unwrap();
+		assert_eq!(entry.priority, 3);
+
+		assert!(parse_message(record(&[("PRIORITY", "warning")])).unwrap_err().contains("PRIORITY"));
+		assert!(parse_message(record(&[("PRIORITY", "9")])).unwrap_err().contains("PRIORITY"));
+		assert!(parse_message(record(&[("_SOURCE_REALTIME_TIMESTAMP", "yesterday")])).unwrap_err().contains("_SOURCE_REALTIME_TIMESTAMP"));
+		assert!(parse_message(record(&[("_SOURCE_REALTIME_TIMESTAMP", &u64::MAX.to_string())])).is_err());
+	}
+
+	#[test]
+	fn rejects_binary_fields_longer_than_the_input() {
+		let mut data = b"MESSAGE\n".to_vec();
+		data.extend_from_slice(&u64::MAX.to_le_bytes());
+		data.extend_from_slice(b"hello\n");
+		assert!(parse_export(&data).is_err());
+
+		let mut data = b"MESSAGE\n".to_vec();
+		data.extend_from_slice(&5u64.to_le_bytes());
+		data.extend_from_slice(b"hello\n\n");
+		assert_eq!(parse_export(&data).unwrap()[0]["MESSAGE"], "hello");
+	}
 }
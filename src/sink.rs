@@ -0,0 +1,351 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::{Mutex as AsyncMutex, mpsc, Notify};
+use tokio::time::sleep;
+
+use crate::config::SpoolSettings;
+use crate::filter::Route;
+use crate::journal::{ack_entries, LogEntry};
+use crate::spool::{Queued, Spool};
+
+/// Most the retry delay grows to, as a multiple of `Sink::retry_seconds`
+const MAX_RETRY_COUNT: u64 = 64;
+
+/// Outcome of delivering a single message
+pub enum Delivery<M> {
+	Sent,
+	/// Delivery failed, keep this (possibly rewritten) message for the next flush
+	Retry(M),
+	/// The destination refused the message for good, drop it and send a notice with this reason instead
+	Drop(String),
+}
+
+/// An output destination for filtered log entries.
+/// Implementors only format and deliver; buffering, flush scheduling, retrying and spooling
+/// failed messages are shared by every sink through `spawn`
+pub trait Sink: Send + Sync + 'static {
+	/// Serializable so undelivered messages can be spooled to disk
+	type Message: Serialize + DeserializeOwned + Send + Sync + 'static;
+
+	/// Destination name that rule groups route entries to this sink with
+	fn name(&self) -> &str;
+
+	/// Whether entries without a named destination are delivered here
+	fn is_default(&self) -> bool {
+		true
+	}
+
+	/// Seconds to wait after the first entry of a batch arrives before flushing it
+	fn flush_seconds(&self) -> u32;
+
+	/// Base delay between retries of failed deliveries, doubled after each failure up to `MAX_RETRY_COUNT` times this
+	fn retry_seconds(&self) -> u32 {
+		self.flush_seconds()
+	}
+
+	/// Entries at or above this priority are flushed right away
+	fn urgent_priority(&self) -> u8 {
+		2
+	}
+
+	/// Whether urgent entries are flushed on their own, leaving the rest of the batch to its
+	/// schedule, rather than taking the whole batch along
+	fn flush_urgent_alone(&self) -> bool {
+		false
+	}
+
+	/// Format a batch of entries into messages ready for delivery
+	fn format(&self, entries: &[LogEntry]) -> Vec<Self::Message>;
+
+	/// Join two consecutive messages into one, if the destination allows it.
+	/// Used to catch up on a backlog of unsent messages with fewer deliveries
+	fn combine(&self, _previous: &Self::Message, _next: &Self::Message) -> Option<Self::Message> {
+		None
+	}
+
+	/// A plain text notice from telelog itself, such as data being dropped from the spool
+	fn notice(&self, text: &str) -> Self::Message;
+
+	fn deliver(&self, message: &Self::Message) -> impl Future<Output = Delivery<Self::Message>> + Send;
+}
+
+/// Snapshot of a sink's queues, reported by the `/status` bot command
+pub struct SinkStatus {
+	pub buffered: usize,
+	pub unsent: usize,
+	pub retry_count: u64,
+}
+
+trait Runner: Send + Sync {
+	fn flush(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+	fn status(&self) -> Pin<Box<dyn Future<Output = SinkStatus> + Send + '_>>;
+}
+
+/// A running sink, as seen by the rest of the program
+pub struct SinkHandle {
+	name: String,
+	is_default: bool,
+	tx: mpsc::Sender<LogEntry>,
+	runner: Arc<dyn Runner>,
+}
+
+impl SinkHandle {
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	/// Whether an entry with this route should be delivered by this sink
+	pub fn accepts(&self, route: &Route) -> bool {
+		match route {
+			Route::Drop => false,
+			Route::Default => self.is_default,
+			Route::To(destinations) => destinations.contains(&self.name),
+		}
+	}
+
+	pub async fn send(&self, entry: LogEntry) {
+		if let Err(e) = self.tx.send(entry).await {
+			println!("[{}] Error in message channel: {}", self.name, e);
+		}
+	}
+
+	pub async fn flush(&self) {
+		self.runner.flush().await;
+	}
+
+	pub async fn status(&self) -> SinkStatus {
+		self.runner.status().await
+	}
+}
+
+struct SinkRunner<S: Sink> {
+	sink: S,
+	entry_buffer: AsyncMutex<Vec<LogEntry>>,
+	// urgent entries waiting to be flushed on their own, see `Sink::flush_urgent_alone`
+	urgent_buffer: AsyncMutex<Vec<LogEntry>>,
+	unsent_messages: AsyncMutex<Vec<Queued<S::Message>>>,
+	// length of `unsent_messages`, which stays locked while a flush delivers, for `/status` to read
+	unsent_count: AtomicUsize,
+	spool: Option<Spool>,
+	// entries flushed but not yet confirmed delivered, acknowledged once nothing is left unsent
+	pending_acks: AsyncMutex<Vec<u64>>,
+	retry_flag: Notify,
+	retry_count: AsyncMutex<u64>,
+}
+
+impl<S: Sink> Runner for SinkRunner<S> {
+	fn flush(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+		Box::pin(async move {
+			self.flush_urgent().await;
+			self.flush_buffer().await;
+		})
+	}
+
+	fn status(&self) -> Pin<Box<dyn Future<Output = SinkStatus> + Send + '_>> {
+		Box::pin(async move {
+			SinkStatus {
+				buffered: self.entry_buffer.lock().await.len() + self.urgent_buffer.lock().await.len(),
+				unsent: self.unsent_count.load(Ordering::Relaxed),
+				retry_count: *self.retry_count.lock().await,
+			}
+		})
+	}
+}
+
+impl<S: Sink> SinkRunner<S> {
+	async fn flush_buffer(&self) {
+		let mut buffer = self.entry_buffer.lock().await;
+		let entries = std::mem::take(&mut *buffer);
+		drop(buffer); // release the lock
+
+		self.flush_entries(entries).await;
+	}
+
+	async fn flush_urgent(&self) {
+		let entries = std::mem::take(&mut *self.urgent_buffer.lock().await);
+		if !entries.is_empty() {
+			self.flush_entries(entries).await;
+		}
+	}
+
+	async fn flush_entries(&self, entries: Vec<LogEntry>) {
+		let mut old_unsent_messages = self.unsent_messages.lock().await;
+
+		if entries.is_empty() && old_unsent_messages.is_empty() {
+			eprintln!("[{}] flush was ran, but buffer was empty", self.sink.name());
+			return
+		}
+
+		let mut pending_acks = self.pending_acks.lock().await;
+		pending_acks.extend(entries.iter().filter_map(|entry| entry.seq));
+
+		let mut queue = std::mem::take(&mut *old_unsent_messages);
+		queue.extend(self.sink.format(&entries).into_iter().map(Queued::now));
+		let mut failed_unsent_messages: Vec<Queued<S::Message>> = Vec::new();
+
+		// send the messages in order, once one fails keep the rest for the next flush
+		for queued in self.combine(queue) {
+			if !failed_unsent_messages.is_empty() {
+				failed_unsent_messages.push(queued);
+				continue
+			}
+			match self.sink.deliver(&queued.message).await {
+				Delivery::Sent => {},
+				Delivery::Retry(message) => failed_unsent_messages.push(Queued { queued_at: queued.queued_at, message }),
+				Delivery::Drop(reason) => {
+					eprintln!("[{}] Dropped a message the destination refused: {}", self.sink.name(), reason);
+					let notice = self.sink.notice(&format!("telelog dropped a message that {} refused: {}", self.sink.name(), reason));
+					match self.sink.deliver(&notice).await {
+						Delivery::Sent => {},
+						Delivery::Retry(notice) => failed_unsent_messages.push(Queued::now(notice)),
+						Delivery::Drop(reason) => eprintln!("[{}] The notice was refused too: {}", self.sink.name(), reason),
+					}
+				},
+			}
+		}
+
+		let mut retry_count = self.retry_count.lock().await;
+		if failed_unsent_messages.is_empty() {
+			*retry_count = 1;
+			if let Some(spool) = &self.spool {
+				spool.save(&failed_unsent_messages);
+			}
+			ack_entries(&std::mem::take(&mut *pending_acks));
+		} else {
+			*retry_count = (*retry_count * 2).min(MAX_RETRY_COUNT);
+			self.retry_flag.notify_one();
+
+			if let Some(spool) = &self.spool {
+				let dropped = spool.enforce_limits(&mut failed_unsent_messages);
+				if dropped > 0 {
+					eprintln!("[{}] Spool limit reached, dropped {} undelivered messages", self.sink.name(), dropped);
+					let notice = format!("telelog dropped {} undelivered messages, the spool limit for '{}' was reached", dropped, self.sink.name());
+					failed_unsent_messages.insert(0, Queued::now(self.sink.notice(&notice)));
+				}
+				// once spooled the entries survive a restart, so the journal cursor can move past them
+				if spool.save(&failed_unsent_messages) {
+					ack_entries(&std::mem::take(&mut *pending_acks));
+				}
+			}
+		}
+
+		self.unsent_count.store(failed_unsent_messages.len(), Ordering::Relaxed);
+		*old_unsent_messages = failed_unsent_messages;
+	}
+
+	/// Join consecutive messages where the sink allows it, keeping the earliest queue time
+	fn combine(&self, queue: Vec<Queued<S::Message>>) -> Vec<Queued<S::Message>> {
+		let mut combined: Vec<Queued<S::Message>> = Vec::new();
+		for queued in queue {
+			if let Some(last) = combined.last_mut() {
+				if let Some(message) = self.sink.combine(&last.message, &queued.message) {
+					last.message = message;
+					continue
+				}
+			}
+			combined.push(queued);
+		}
+		combined
+	}
+}
+
+/// Start the batching and retry tasks for a sink, returning the handle entries are sent through
+pub fn spawn<S: Sink>(sink: S, spool_settings: &SpoolSettings) -> SinkHandle {
+	let name = sink.name().to_string();
+	let is_default = sink.is_default();
+	let flush_seconds = sink.flush_seconds();
+	let retry_seconds = sink.retry_seconds();
+	let urgent_priority = sink.urgent_priority();
+	let urgent_alone = sink.flush_urgent_alone();
+
+	let spool = Spool::new(spool_settings, &name);
+	let spooled: Vec<Queued<S::Message>> = spool.as_ref().map(|spool| spool.load()).unwrap_or_default();
+
+	let runner = Arc::new(SinkRunner {
+		sink,
+		entry_buffer: AsyncMutex::new(Vec::new()),
+		urgent_buffer: AsyncMutex::new(Vec::new()),
+		unsent_messages: AsyncMutex::new(Vec::new()),
+		unsent_count: AtomicUsize::new(spooled.len()),
+		spool,
+		pending_acks: AsyncMutex::new(Vec::new()),
+		retry_flag: Notify::new(),
+		retry_count: AsyncMutex::new(1),
+	});
+	let (tx, mut rx) = mpsc::channel::<LogEntry>(40);
+
+	// replay whatever a previous run could not deliver before anything new
+	if !spooled.is_empty() {
+		println!("[{}] Replaying {} spooled messages", name, spooled.len());
+		let runner = runner.clone();
+		tokio::spawn(async move {
+			runner.unsent_messages.lock().await.extend(spooled);
+			runner.flush_buffer().await;
+		});
+	}
+
+	// spawn a task to process messages as they are sent from the main task
+	let receiver = runner.clone();
+	tokio::spawn(async move {
+		while let Some(entry) = rx.recv().await {
+			let priority = entry.priority;
+			if priority <= urgent_priority && urgent_alone {
+				receiver.urgent_buffer.lock().await.push(entry);
+				let runner = receiver.clone();
+				tokio::spawn(async move {
+					runner.flush_urgent().await;
+				});
+				continue
+			}
+
+			let mut buffer = receiver.entry_buffer.lock().await;
+			buffer.push(entry);
+			if priority <= urgent_priority {
+				drop(buffer); // release the lock
+				// if this is a critical entry, flush the buffer immediately
+				let runner = receiver.clone();
+				tokio::spawn(async move {
+					runner.flush_buffer().await;
+				});
+				continue
+			}
+			// otherwise schedule a flush if this is the first message in the buffer
+			if buffer.len() == 1 {
+				drop(buffer); // release the lock
+				let runner = receiver.clone();
+				tokio::spawn(async move {
+					sleep(Duration::from_secs(flush_seconds as u64)).await;
+					runner.flush_buffer().await;
+				});
+			}
+		}
+	});
+
+	// task to handle retrying failed flushes
+	let retrier = runner.clone();
+	tokio::spawn(async move {
+		loop {
+			retrier.retry_flag.notified().await;
+			let retry_count = *retrier.retry_count.lock().await;
+			sleep(Duration::from_secs(retry_count * retry_seconds as u64)).await;
+			let runner = retrier.clone();
+			tokio::spawn(async move {
+				runner.flush_buffer().await;
+			});
+		}
+	});
+
+	println!("[{}] initialised", name);
+
+	SinkHandle {
+		name,
+		is_default,
+		tx,
+		runner,
+	}
+}
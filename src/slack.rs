@@ -0,0 +1,292 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::json;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::sleep;
+
+use crate::config::{WebhookSettings};
+use crate::helpers::{batch_lines, collapse_repeats, is_permanent_failure, pack_lines, visible_len, MAX_PAUSE};
+use crate::journal::LogEntry;
+use crate::sink::{Delivery, Sink};
+use crate::template::{Formats, LineFormat};
+
+/// Name of the destination built from the top level `slack.webhook_url`
+const DEFAULT_DESTINATION: &str = "slack";
+
+/// Slack truncates messages past 40,000 characters and advises staying under 4,000.
+/// Lines are measured before escaping, so even text that is all `&` stays far below the hard limit
+const MESSAGE_LIMIT: usize = 4000;
+
+/// The fences around each message's code block
+const CODE_OPEN: &str = "```\n";
+const CODE_CLOSE: &str = "```";
+
+/// Posts batches to a Slack incoming webhook as mrkdwn code blocks
+#[derive(Debug)]
+pub struct SlackSink {
+	name: String,
+	webhook_url: String,
+	flush_seconds: u16,
+	dedup_window: Option<Duration>,
+	line_format: LineFormat,
+	http: reqwest::Client,
+	send_lock: Arc<AsyncMutex<()>>,
+}
+
+/// Names rule groups can route to: the default webhook, if set, and every named destination
+pub fn destination_names(settings: &WebhookSettings) -> Vec<String> {
+	let mut names: Vec<String> = settings.webhook_url.iter().map(|_| DEFAULT_DESTINATION.to_string()).collect();
+	names.extend(settings.destinations.keys().cloned());
+	names
+}
+
+/// Build a sink for the default webhook and one for each named destination
+pub fn sinks(settings: &WebhookSettings, formats: &Formats) -> Vec<SlackSink> {
+	let http = reqwest::Client::new();
+	let dedup_window = formats.dedup_window;
+
+	let mut webhooks: Vec<(String, String)> = settings.webhook_url.iter().map(|url| (DEFAULT_DESTINATION.to_string(), url.clone())).collect();
+	webhooks.extend(settings.destinations.iter().map(|(name, destination)| (name.clone(), destination.webhook_url.clone())));
+
+	webhooks.into_iter().map(|(name, webhook_url)| {
+		let line_format = formats.line_format(&name);
+
+		SlackSink {
+			name,
+			webhook_url,
+			flush_seconds: settings.flush_seconds.unwrap_or(5),
+			dedup_window,
+			line_format,
+			http: http.clone(),
+			// webhooks are rate limited one by one
+			send_lock: Arc::new(AsyncMutex::new(())),
+		}
+	}).collect()
+}
+
+/// Escape the characters Slack treats as markup, and break up backtick fences so
+/// an entry cannot close the code block it is in
+fn escape_mrkdwn(text: &str) -> String {
+	text.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace("```", "`\u{200b}`\u{200b}`")
+}
+
+impl SlackSink {
+	async fn post(&self, text: &str) -> Result<reqwest::Response, reqwest::Error> {
+		let _guard = self.send_lock.clone().lock_owned().await;
+		let response = self.http.post(&self.webhook_url)
+			.header(reqwest::header::CONTENT_TYPE, "application/json")
+			.body(json!({ "text": text }).to_string())
+			.send()
+			.await;
+
+		// incoming webhooks allow about one message a second
+		tokio::spawn(async move {
+			sleep(Duration::from_secs(1)).await;
+			drop(_guard);
+		});
+
+		response
+	}
+}
+
+impl Sink for SlackSink {
+	type Message = String;
+
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn is_default(&self) -> bool {
+		self.name == DEFAULT_DESTINATION
+	}
+
+	fn flush_seconds(&self) -> u32 {
+		self.flush_seconds.into()
+	}
+
+	fn format(&self, entries: &[LogEntry]) -> Vec<String> {
+		let lines = batch_lines(&collapse_repeats(entries, self.dedup_window), &self.line_format);
+		pack_lines(&lines, MESSAGE_LIMIT - CODE_OPEN.len() - CODE_CLOSE.len()).into_iter().map(|lines| {
+			let mut message = String::from(CODE_OPEN);
+			for line in lines {
+				message.push_str(&escape_mrkdwn(&line));
+				message.push('\n');
+			}
+			message.push_str(CODE_CLOSE);
+			message
+		}).collect()
+	}
+
+	fn combine(&self, previous: &String, next: &String) -> Option<String> {
+		let previous = previous.strip_suffix(CODE_CLOSE)?;
+		let next = next.strip_prefix(CODE_OPEN)?;
+		// entities count as the character they stand for
+		if visible_len(previous) + visible_len(next) > MESSAGE_LIMIT {
+			return None
+		}
+		Some(format!("{}{}", previous, next))
+	}
+
+	fn notice(&self, text: &str) -> String {
+		escape_mrkdwn(&format!(":warning: {}", text))
+	}
+
+	async fn deliver(&self, message: &String) -> Delivery<String> {
+		let response = match self.post(message).await {
+			Ok(response) => response,
+			Err(e) => {
+				eprintln!("[slack] Failed: {}", e);
+				return Delivery::Retry(message.clone())
+			}
+		};
+
+		if response.status().is_success() {
+			return Delivery::Sent
+		}
+
+		let status = response.status();
+		let retry_after = response.headers().get("retry-after")
+			.and_then(|value| value.to_str().ok())
+			.and_then(|value| value.parse::<u64>().ok());
+		let text = response.text().await.unwrap_or_default();
+
+		match (status.as_u16(), retry_after) {
+			(429, Some(retry_after)) => {
+				let retry_after = Duration::from_secs(retry_after).min(MAX_PAUSE);
+				eprintln!("[slack] API response 429: pausing messages for {} seconds", retry_after.as_secs());
+				// take the lock before returning, so the next post is queued behind the pause
+				let _guard = self.send_lock.clone().lock_owned().await;
+				tokio::spawn(async move {
+					sleep(retry_after).await;
+					drop(_guard);
+				});
+			},
+			_ if is_permanent_failure(status) => {
+				println!("[slack] API response {}: {:?}", status, text);
+				return Delivery::Drop(format!("{} {}", status, text))
+			},
+			_ => println!("[slack] API response {}: {:?}", status, text),
+		}
+
+		Delivery::Retry(message.clone())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+	use tokio::time::Instant;
+
+	use chrono::Local;
+
+	use super::*;
+	use crate::config::{WebhookDestination, SpoolSettings};
+	use crate::mock_http::MockServer;
+	use crate::sink;
+
+	fn settings(webhook_url: &str) -> WebhookSettings {
+		WebhookSettings {
+			webhook_url: None,
+			flush_seconds: Some(1),
+			destinations: [("ops".to_string(), WebhookDestination { webhook_url: format!("{}/services/T0/B0/XYZ", webhook_url) })].into(),
+		}
+	}
+
+	fn entry(message: &str) -> LogEntry {
+		LogEntry::new(6, Local::now(), "test".to_string(), message.to_string(), BTreeMap::new())
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn posts_batches_as_code_blocks() {
+		let server = MockServer::start(Vec::new()).await;
+		let slack = sinks(&settings(&server.url), &Formats::default()).remove(0);
+		assert_eq!(slack.name(), "ops");
+		assert!(!slack.is_default());
+		let handle = sink::spawn(slack, &SpoolSettings::default());
+
+		for message in ["first", "<@here> & ```rm -rf```"] {
+			handle.send(entry(message)).await;
+		}
+		server.wait_for(1).await;
+
+		let requests = server.requests();
+		assert_eq!(requests.len(), 1);
+		assert_eq!(requests[0].path, "/services/T0/B0/XYZ");
+		assert_eq!(requests[0].headers["content-type"], "application/json");
+
+		let text = requests[0].json()["text"].as_str().unwrap().to_string();
+		assert!(text.starts_with("```\n") && text.ends_with("\n```"));
+		assert_eq!(text.matches("```").count(), 2);
+		assert!(text.contains("test: first\n"));
+		assert!(text.contains("test: &lt;@here&gt; &amp; `\u{200b}`\u{200b}`rm -rf`\u{200b}`\u{200b}`\n"));
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn splits_long_batches() {
+		let slack = sinks(&settings("http://localhost"), &Formats::default()).remove(0);
+		let entries: Vec<LogEntry> = (0..50).map(|i| entry(&format!("{} {}", i, "x".repeat(300)))).collect();
+
+		let messages = slack.format(&entries);
+		assert!(messages.len() > 1);
+		for message in messages.iter() {
+			assert!(message.chars().count() <= MESSAGE_LIMIT);
+		}
+		assert!(slack.combine(&messages[0], &messages[1]).is_none());
+		assert_eq!(messages.iter().map(|message| message.matches(&"x".repeat(300)).count()).sum::<usize>(), 50);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn pauses_sending_after_429() {
+		let server = MockServer::start(vec![
+			(429, vec![("Retry-After", "2".to_string())], "rate_limited".to_string()),
+		]).await;
+		let slack = sinks(&settings(&server.url), &Formats::default()).remove(0);
+		let message = "```\nrate limited\n```".to_string();
+
+		let started = Instant::now();
+		match slack.deliver(&message).await {
+			Delivery::Retry(retry) => assert_eq!(retry, message),
+			Delivery::Sent => panic!("429 reported as sent"),
+			Delivery::Drop(reason) => panic!("429 dropped: {}", reason),
+		}
+		assert!(matches!(slack.deliver(&message).await, Delivery::Sent));
+
+		let requests = server.requests();
+		assert_eq!(requests.len(), 2);
+		assert!(requests[1].received.duration_since(started) >= Duration::from_secs(2));
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn drops_message_refused_for_good() {
+		let server = MockServer::start(vec![
+			(404, Vec::new(), "no_service".to_string()),
+		]).await;
+		let slack = sinks(&settings(&server.url), &Formats::default()).remove(0);
+
+		match slack.deliver(&"```\ngone\n```".to_string()).await {
+			Delivery::Drop(reason) => assert!(reason.contains("no_service")),
+			_ => panic!("404 not dropped"),
+		}
+		assert_eq!(server.requests().len(), 1);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn caps_the_pause_a_429_asks_for() {
+		let server = MockServer::start(vec![
+			(429, vec![("Retry-After", u64::MAX.to_string())], "rate_limited".to_string()),
+		]).await;
+		let slack = sinks(&settings(&server.url), &Formats::default()).remove(0);
+		let message = "```\nrate limited\n```".to_string();
+
+		let started = Instant::now();
+		assert!(matches!(slack.deliver(&message).await, Delivery::Retry(_)));
+		assert!(matches!(slack.deliver(&message).await, Delivery::Sent));
+
+		let waited = server.requests()[1].received.duration_since(started);
+		assert!(waited >= MAX_PAUSE && waited < MAX_PAUSE + Duration::from_secs(60));
+	}
+}
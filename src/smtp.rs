@@ -0,0 +1,425 @@
+use std::time::Duration;
+
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::{SmtpSettings};
+use crate::helpers::{collapse_repeats, colour_hex, escape_html, parse_duration, Repeated};
+use crate::journal::LogEntry;
+use crate::sink::{Delivery, Sink};
+use crate::template::{priority_name, Formats, LineFormat};
+
+/// Name of the destination built from the top level `smtp.to`
+const DEFAULT_DESTINATION: &str = "email";
+
+const DEFAULT_PORT: u16 = 587;
+const DEFAULT_DIGEST: Duration = Duration::from_secs(3600);
+
+/// Failed emails are retried this often at first, however long the digest period
+const RETRY_SECONDS: u32 = 30;
+
+/// Longest entry message quoted in the subject of an urgent email
+const SUBJECT_MESSAGE_LIMIT: usize = 80;
+
+/// How the connection to the mail server is secured
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Security {
+	/// Upgrade with STARTTLS, refusing servers that do not offer it
+	StartTls,
+	/// No encryption, for a relay on the same host or network
+	Plain,
+}
+
+impl Security {
+	pub fn parse(security: &str) -> Result<Self, String> {
+		match security {
+			"starttls" | "STARTTLS" => Ok(Security::StartTls),
+			"plain" | "none" => Ok(Security::Plain),
+			_ => Err(format!("unknown security '{}', use starttls or plain", security)),
+		}
+	}
+}
+
+/// An email ready to send, with a plain text body and an HTML twin
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Email {
+	pub subject: String,
+	pub text: String,
+	pub html: String,
+}
+
+/// Emails urgent entries as they arrive and everything else as a periodic digest
+pub struct SmtpSink {
+	name: String,
+	from: Mailbox,
+	to: Vec<Mailbox>,
+	digest_seconds: u32,
+	urgent_priority: u8,
+	dedup_window: Option<Duration>,
+	line_format: LineFormat,
+	transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+/// Names rule groups can route to: the default recipients, if any, and every named destination
+pub fn destination_names(settings: &SmtpSettings) -> Vec<String> {
+	let mut names: Vec<String> = Vec::new();
+	if !settings.to.is_empty() {
+		names.push(DEFAULT_DESTINATION.to_string());
+	}
+	names.extend(settings.destinations.keys().cloned());
+	names
+}
+
+pub fn transport(settings: &SmtpSettings, host: &str) -> Result<AsyncSmtpTransport<Tokio1Executor>, String> {
+	let security = settings.security.as_deref().map(Security::parse).transpose()?.unwrap_or(Security::StartTls);
+	let builder = match security {
+		Security::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host).map_err(|e| e.to_string())?,
+		Security::Plain => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+	};
+	let builder = builder.port(settings.port.unwrap_or(DEFAULT_PORT));
+
+	Ok(match (&settings.username, &settings.password) {
+		(Some(username), Some(password)) => builder.credentials(Credentials::new(username.clone(), password.clone())),
+		_ => builder,
+	}.build())
+}
+
+/// Build a sink for the default recipients and one for each named destination, nothing if no host is set.
+/// Settings that would leave email unable to send at all are an error
+pub fn sinks(settings: &SmtpSettings, formats: &Formats) -> Result<Vec<SmtpSink>, String> {
+	let host = match &settings.host {
+		Some(host) => host,
+		None => return Ok(Vec::new()),
+	};
+	let transport = transport(settings, host)?;
+	let from: Mailbox = match settings.from.as_deref().map(str::parse) {
+		Some(Ok(from)) => from,
+		Some(Err(e)) => return Err(format!("invalid from address: {}", e)),
+		None => return Err("no from address set".to_string()),
+	};
+	let digest = settings.digest.as_deref().and_then(parse_duration).unwrap_or(DEFAULT_DIGEST);
+	let dedup_window = formats.dedup_window;
+
+	let mut recipients: Vec<(String, &Vec<String>)> = Vec::new();
+	if !settings.to.is_empty() {
+		recipients.push((DEFAULT_DESTINATION.to_string(), &settings.to));
+	}
+	recipients.extend(settings.destinations.iter().map(|(name, destination)| (name.clone(), &destination.to)));
+
+	Ok(recipients.into_iter().filter_map(|(name, addresses)| {
+		let line_format = formats.line_format(&name);
+		let to = addresses.iter().filter_map(|address| match address.parse::<Mailbox>() {
+			Ok(mailbox) => Some(mailbox),
+			Err(e) => {
+				println!("[smtp] Skipping invalid recipient '{}' of {}: {}", address, name, e);
+				None
+			}
+		}).collect::<Vec<Mailbox>>();
+		if to.is_empty() {
+			println!("[smtp] No valid recipients for {}, not sending it email", name);
+			return None
+		}
+
+		Some(SmtpSink {
+			name,
+			from: from.clone(),
+			to,
+			digest_seconds: u32::try_from(digest.as_secs()).unwrap_or(u32::MAX).max(1),
+			urgent_priority: settings.urgent_priority.unwrap_or(2),
+			dedup_window,
+			line_format,
+			transport: transport.clone(),
+		})
+	}).collect())
+}
+
+impl SmtpSink {
+	/// Urgent batches are named after their most severe entry, digests after their size
+	fn subject(&self, batch: &[Repeated]) -> String {
+		let count: usize = batch.iter().map(|repeated| repeated.count).sum();
+		let most_severe = batch.iter().min_by_key(|repeated| repeated.entry.priority);
+		match most_severe {
+			Some(repeated) if repeated.entry.priority <= self.urgent_priority => {
+				let entry = repeated.entry;
+				let mut message: String = entry.message.lines().next().unwrap_or_default().chars().take(SUBJECT_MESSAGE_LIMIT).collect();
+				if message.len() < entry.message.len() {
+					message.push('…');
+				}
+				let mut subject = format!("[telelog] {}: {}: {}", priority_name(entry.priority).to_uppercase(), entry.identifier, message);
+				if count > 1 {
+					subject.push_str(&format!(" (+{} more)", count - 1));
+				}
+				subject
+			},
+			_ => {
+				let hostname = batch.first().and_then(|repeated| repeated.entry.get_field("_HOSTNAME").ok());
+				match hostname {
+					Some(hostname) => format!("[telelog] Digest of {} entries from {}", count, hostname),
+					None => format!("[telelog] Digest of {} entries", count),
+				}
+			},
+		}
+	}
+}
+
+impl Sink for SmtpSink {
+	type Message = Email;
+
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn is_default(&self) -> bool {
+		self.name == DEFAULT_DESTINATION
+	}
+
+	fn flush_seconds(&self) -> u32 {
+		self.digest_seconds
+	}
+
+	fn retry_seconds(&self) -> u32 {
+		RETRY_SECONDS
+	}
+
+	fn urgent_priority(&self) -> u8 {
+		self.urgent_priority
+	}
+
+	fn flush_urgent_alone(&self) -> bool {
+		true
+	}
+
+	fn format(&self, entries: &[LogEntry]) -> Vec<Email> {
+		let batch = collapse_repeats(entries, self.dedup_window);
+		if batch.is_empty() {
+			return Vec::new()
+		}
+
+		let header = self.line_format.header.as_ref().map(|header| self.line_format.batch_line(header, &batch));
+		let footer = self.line_format.footer.as_ref().map(|footer| self.line_format.batch_line(footer, &batch));
+		let lines: Vec<(u8, String)> = batch.iter().map(|repeated| (repeated.entry.priority, self.line_format.line(repeated))).collect();
+
+		let mut text = String::new();
+		let mut html = String::from("<html><body style=\"font-family: monospace; white-space: pre-wrap\">\n");
+		if let Some(header) = &header {
+			text.push_str(&format!("{}\n", header));
+			html.push_str(&format!("<p>{}</p>\n", escape_html(header)));
+		}
+		for (priority, line) in lines.iter() {
+			text.push_str(&format!("{}\n", line));
+			html.push_str(&format!("<div style=\"border-left: 4px solid #{:06x}; padding-left: 6px\">{}</div>\n", colour_hex(*priority), escape_html(line)));
+		}
+		if let Some(footer) = &footer {
+			text.push_str(&format!("{}\n", footer));
+			html.push_str(&format!("<p>{}</p>\n", escape_html(footer)));
+		}
+		html.push_str("</body></html>\n");
+
+		vec![Email { subject: self.subject(&batch), text, html }]
+	}
+
+	// emails are never combined: each digest covers its own period
+
+	fn notice(&self, text: &str) -> Email {
+		Email {
+			subject: "[telelog] Notice".to_string(),
+			text: format!("{}\n", text),
+			html: format!("<html><body><p>{}</p></body></html>\n", escape_html(text)),
+		}
+	}
+
+	async fn deliver(&self, message: &Email) -> Delivery<Email> {
+		let mut builder = lettre::Message::builder()
+			.from(self.from.clone())
+			.subject(&message.subject);
+		for to in self.to.iter() {
+			builder = builder.to(to.clone());
+		}
+		let email = match builder.multipart(MultiPart::alternative_plain_html(message.text.clone(), message.html.clone())) {
+			Ok(email) => email,
+			Err(e) => {
+				eprintln!("[smtp] Failed to build email: {}", e);
+				return Delivery::Drop(format!("the email could not be built: {}", e))
+			}
+		};
+
+		match self.transport.send(email).await {
+			Ok(_) => Delivery::Sent,
+			// a 5xx reply, the server will refuse the same email again
+			Err(e) if e.is_permanent() => {
+				eprintln!("[smtp] Refused: {}", e);
+				Delivery::Drop(e.to_string())
+			},
+			Err(e) => {
+				eprintln!("[smtp] Failed: {}", e);
+				Delivery::Retry(message.clone())
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+
+	use chrono::Local;
+	use tokio::time::Instant;
+
+	use super::*;
+	use crate::config::{FormatSettings, SmtpDestination, SpoolSettings};
+	use crate::mock_smtp::MockSmtpServer;
+	use crate::sink;
+
+	fn settings(server: &MockSmtpServer) -> SmtpSettings {
+		SmtpSettings {
+			host: Some("127.0.0.1".to_string()),
+			port: Some(server.port),
+			security: Some("plain".to_string()),
+			username: Some("telelog".to_string()),
+			password: Some("secret".to_string()),
+			from: Some("telelog <telelog@example.org>".to_string()),
+			to: vec!["ops@example.org".to_string()],
+			digest: Some("2s".to_string()),
+			urgent_priority: None,
+			destinations: [("security".to_string(), SmtpDestination { to: vec!["sec@example.org".to_string()] })].into(),
+		}
+	}
+
+	fn format() -> Formats {
+		Formats::new(&FormatSettings {
+			template: Some("{priority_name} {identifier}: {message}".to_string()),
+			..Default::default()
+		}).unwrap()
+	}
+
+	fn entry(priority: u8, message: &str) -> LogEntry {
+		LogEntry::new(priority, Local::now(), "test".to_string(), message.to_string(), BTreeMap::new())
+	}
+
+	/// lettre sends the HTML part quoted-printable, with soft line breaks and `=` escaped
+	fn decode_quoted_printable(text: &str) -> String {
+		let text = text.replace("=\r\n", "");
+		let mut decoded = Vec::new();
+		let mut bytes = text.bytes();
+		while let Some(byte) = bytes.next() {
+			match byte {
+				b'=' => {
+					let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+					decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).unwrap(), 16).unwrap());
+				},
+				byte => decoded.push(byte),
+			}
+		}
+		String::from_utf8(decoded).unwrap()
+	}
+
+	fn default_sink(settings: &SmtpSettings) -> SmtpSink {
+		sinks(settings, &format()).unwrap().into_iter().find(|sink| sink.is_default()).unwrap()
+	}
+
+	// lettre's connection setup doesn't complete on a paused clock, so this waits on the mock server in real time
+	#[tokio::test]
+	async fn sends_urgent_entries_right_away_and_the_rest_as_a_digest() {
+		let server = MockSmtpServer::start(0).await;
+		let handle = sink::spawn(default_sink(&settings(&server)), &SpoolSettings::default());
+
+		let started = Instant::now();
+		handle.send(entry(6, "routine")).await;
+		handle.send(entry(2, "disk on fire")).await;
+		handle.send(entry(4, "<b>not bold</b> & more")).await;
+		server.wait_for(1).await;
+		assert!(started.elapsed() < Duration::from_secs(2));
+
+		{
+			let mails = server.mails();
+			assert_eq!(mails.len(), 1);
+			// AUTH PLAIN of "\0telelog\0secret"
+			assert_eq!(mails[0].auth.as_deref(), Some("PLAIN AHRlbGVsb2cAc2VjcmV0"));
+			assert_eq!(mails[0].from, "telelog@example.org");
+			assert_eq!(mails[0].to, vec!["ops@example.org"]);
+			assert!(mails[0].data.contains("Subject: [telelog] CRIT: test: disk on fire\r\n"));
+			assert!(mails[0].data.contains("crit test: disk on fire"));
+			assert!(!mails[0].data.contains("routine"));
+		}
+
+		server.wait_for(2).await;
+		assert!(started.elapsed() >= Duration::from_secs(2));
+		let mails = server.mails();
+		assert_eq!(mails.len(), 2);
+		let digest = &mails[1].data;
+		assert!(digest.contains("Subject: [telelog] Digest of 2 entries\r\n"));
+		assert!(digest.contains("Content-Type: multipart/alternative"));
+		assert!(digest.contains("Content-Type: text/plain; charset=utf-8"));
+		assert!(digest.contains("Content-Type: text/html; charset=utf-8"));
+		assert!(digest.contains("info test: routine\r\nwarning test: <b>not bold</b> & more\r\n"));
+		let html = decode_quoted_printable(digest.split_once("quoted-printable\r\n\r\n").unwrap().1);
+		assert!(html.contains("#4169e1; padding-left: 6px\">info test: routine</div>"));
+		assert!(html.contains("warning test: &lt;b&gt;not bold&lt;/b&gt; &amp; more</div>"));
+	}
+
+	#[tokio::test]
+	async fn sends_named_destinations_to_their_own_recipients() {
+		let server = MockSmtpServer::start(0).await;
+		let security = sinks(&settings(&server), &format()).unwrap().into_iter().find(|sink| sink.name() == "security").unwrap();
+		assert!(!security.is_default());
+
+		let email = security.format(&[entry(1, "intrusion detected"), entry(1, "another one")]).remove(0);
+		assert_eq!(email.subject, "[telelog] ALERT: test: intrusion detected (+1 more)");
+		assert!(matches!(security.deliver(&email).await, Delivery::Sent));
+
+		let mails = server.mails();
+		assert_eq!(mails[0].to, vec!["sec@example.org"]);
+	}
+
+	#[tokio::test]
+	async fn skips_destinations_without_valid_recipients() {
+		let server = MockSmtpServer::start(0).await;
+		let mut settings = settings(&server);
+		settings.destinations.insert("broken".to_string(), SmtpDestination { to: vec!["not an address".to_string()] });
+
+		let names: Vec<String> = sinks(&settings, &format()).unwrap().iter().map(|sink| sink.name().to_string()).collect();
+		assert_eq!(names.len(), 2);
+		assert!(!names.contains(&"broken".to_string()));
+	}
+
+	#[tokio::test]
+	async fn refuses_settings_that_cant_send() {
+		let server = MockSmtpServer::start(0).await;
+		let mut settings = settings(&server);
+		settings.from = Some("not an address".to_string());
+		assert!(sinks(&settings, &format()).is_err());
+		settings.from = None;
+		assert!(sinks(&settings, &format()).is_err());
+		settings.host = None;
+		assert!(sinks(&settings, &format()).unwrap().is_empty());
+	}
+
+	#[tokio::test]
+	async fn retries_when_the_server_refuses() {
+		let server = MockSmtpServer::start(1).await;
+		let smtp = default_sink(&settings(&server));
+		let email = smtp.notice("spool limit reached");
+
+		match smtp.deliver(&email).await {
+			Delivery::Retry(retry) => assert_eq!(retry, email),
+			Delivery::Sent => panic!("refused email reported as sent"),
+			Delivery::Drop(reason) => panic!("refused email dropped: {}", reason),
+		}
+		assert!(matches!(smtp.deliver(&email).await, Delivery::Sent));
+		assert_eq!(server.mails().len(), 1);
+	}
+
+	#[tokio::test]
+	async fn drops_an_email_the_server_rejects_for_good() {
+		let server = MockSmtpServer::start_refusing(1, "554 5.7.1 Message rejected").await;
+		let smtp = default_sink(&settings(&server));
+
+		match smtp.deliver(&smtp.notice("spool limit reached")).await {
+			Delivery::Drop(reason) => assert!(reason.contains("Message rejected")),
+			_ => panic!("rejected email not dropped"),
+		}
+		assert!(server.mails().is_empty());
+	}
+}
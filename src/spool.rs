@@ -0,0 +1,132 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::Local;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::SpoolSettings;
+use crate::helpers::parse_duration;
+
+/// A message waiting to be delivered, with the unix time it was first queued
+#[derive(Serialize, Deserialize)]
+pub struct Queued<M> {
+	pub queued_at: i64,
+	pub message: M,
+}
+
+impl<M> Queued<M> {
+	pub fn now(message: M) -> Self {
+		Queued {
+			queued_at: Local::now().timestamp(),
+			message,
+		}
+	}
+}
+
+/// On disk copy of one sink's undelivered messages, so they survive restarts.
+/// Bounded by total size and age, dropping the oldest messages first
+pub struct Spool {
+	path: PathBuf,
+	max_bytes: u64,
+	max_age: Duration,
+}
+
+/// Whether a destination name can be used as its spool file name, without leaving the spool directory
+pub fn is_valid_name(name: &str) -> bool {
+	!name.is_empty() && !name.contains(['/', '\0']) && !name.contains("..")
+}
+
+impl Spool {
+	pub fn new(settings: &SpoolSettings, sink_name: &str) -> Option<Self> {
+		let directory = settings.directory.as_ref()?;
+
+		if !is_valid_name(sink_name) {
+			eprintln!("[spool] Not spooling for '{}', destination names can't contain '/' or '..'", sink_name);
+			return None
+		}
+
+		if let Err(e) = std::fs::create_dir_all(directory) {
+			eprintln!("[spool] Failed to create spool directory {}: {}", directory.display(), e);
+			return None
+		}
+
+		Some(Spool {
+			path: directory.join(format!("{}.json", sink_name)),
+			max_bytes: settings.max_bytes.unwrap_or(10 * 1024 * 1024),
+			max_age: settings.max_age.as_deref().and_then(parse_duration).unwrap_or(Duration::from_secs(3 * 24 * 60 * 60)),
+		})
+	}
+
+	/// Read back the messages spooled by a previous run, oldest first
+	pub fn load<M: DeserializeOwned>(&self) -> Vec<Queued<M>> {
+		let text = match std::fs::read_to_string(&self.path) {
+			Ok(text) => text,
+			Err(e) => {
+				if e.kind() != std::io::ErrorKind::NotFound {
+					eprintln!("[spool] Failed to read {}: {}", self.path.display(), e);
+				}
+				return Vec::new()
+			}
+		};
+
+		match serde_json::from_str(&text) {
+			Ok(messages) => messages,
+			Err(e) => {
+				eprintln!("[spool] Discarding unreadable spool {}: {}", self.path.display(), e);
+				Vec::new()
+			}
+		}
+	}
+
+	/// Replace the spooled messages. Returns true once they are safely on disk
+	pub fn save<M: Serialize>(&self, messages: &[Queued<M>]) -> bool {
+		if messages.is_empty() {
+			return match std::fs::remove_file(&self.path) {
+				Ok(_) => true,
+				Err(e) if e.kind() == std::io::ErrorKind::NotFound => true,
+				Err(e) => {
+					eprintln!("[spool] Failed to remove {}: {}", self.path.display(), e);
+					false
+				}
+			}
+		}
+
+		// write to a temporary file and rename over the old one so a crash never leaves a partial spool
+		let tmp_path = self.path.with_extension("tmp");
+		let result = serde_json::to_vec(messages)
+			.map_err(std::io::Error::other)
+			.and_then(|data| std::fs::write(&tmp_path, data))
+			.and_then(|_| std::fs::rename(&tmp_path, &self.path));
+
+		match result {
+			Ok(_) => true,
+			Err(e) => {
+				eprintln!("[spool] Failed to write {}: {}", self.path.display(), e);
+				false
+			}
+		}
+	}
+
+	/// Drop the oldest messages until the rest are within the age and size limits.
+	/// Returns how many were dropped
+	pub fn enforce_limits<M: Serialize>(&self, messages: &mut Vec<Queued<M>>) -> usize {
+		let before = messages.len();
+
+		let oldest_allowed = Local::now().timestamp() - self.max_age.as_secs() as i64;
+		messages.retain(|queued| queued.queued_at >= oldest_allowed);
+
+		let sizes: Vec<u64> = messages.iter()
+			.map(|queued| serde_json::to_vec(queued).map(|data| data.len() as u64).unwrap_or(0))
+			.collect();
+		let mut total: u64 = sizes.iter().sum();
+		let mut excess = 0;
+		while total > self.max_bytes && excess < sizes.len() {
+			total -= sizes[excess];
+			excess += 1;
+		}
+		messages.drain(..excess);
+
+		before - messages.len()
+	}
+}
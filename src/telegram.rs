@@ -1,33 +1,139 @@
-use lazy_static::lazy_static;
-use signal_hook::{consts::{SIGTERM,SIGINT}, iterator::Signals};
 use tokio::time::sleep;
-use tokio::sync::{Mutex as AsyncMutex, mpsc, Notify};
-use reqwest;
+use tokio::sync::Mutex as AsyncMutex;
+use std::sync::Arc;
 use std::time::Duration;
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 use serde_json::Error as JsonError;
-use std::sync::OnceLock;
+use reqwest::multipart::{Form, Part};
 
 use crate::{helpers::*, journal::LogEntry};
-use crate::config::AppSettings;
+use crate::config::{TelegramDestination, TelegramSettings};
+use crate::sink::{Delivery, Sink};
+use crate::template::{Formats, LineFormat};
+
+/// Name of the destination built from the top level `telegram.chat_id`
+pub const DEFAULT_DESTINATION: &str = "default";
+
+const DEFAULT_API_URL: &str = "https://api.telegram.org";
+
+/// Batches that would take more messages than this are sent as a document
+const DEFAULT_DOCUMENT_AFTER: usize = 3;
+
+/// Priority of telelog's own notices, a warning
+const NOTICE_PRIORITY: u8 = 4;
+
+/// Bot API client every Telegram call goes through, against api.telegram.org
+/// or a self-hosted Bot API server
+#[derive(Debug, Clone)]
+pub struct TelegramClient {
+	api_url: String,
+	api_key: String,
+	http: reqwest::Client,
+}
+
+impl TelegramClient {
+	pub fn new(settings: &TelegramSettings) -> Self {
+		TelegramClient {
+			api_url: settings.api_url.as_deref().unwrap_or(DEFAULT_API_URL).trim_end_matches('/').to_string(),
+			api_key: settings.api_key.clone().unwrap(),
+			http: reqwest::Client::new(),
+		}
+	}
+
+	fn method_url(&self, method: &str) -> String {
+		format!("{}/bot{}/{}", self.api_url, self.api_key, method)
+	}
+
+	pub async fn send_message(&self, chat_id: &str, message_thread_id: Option<i64>, text: &str, silent: bool) -> Result<reqwest::Response, reqwest::Error> {
+		let mut form = vec![("chat_id", chat_id.to_string()), ("text", text.to_string()), ("parse_mode", "HTML".to_string())];
+		if let Some(thread_id) = message_thread_id {
+			form.push(("message_thread_id", thread_id.to_string()));
+		}
+		if silent {
+			form.push(("disable_notification", "true".to_string()));
+		}
+
+		self.http.post(self.method_url("sendMessage"))
+			.form(&form)
+			.send()
+			.await
+	}
+
+	pub async fn pin_message(&self, chat_id: &str, message_id: i64, silent: bool) -> Result<reqwest::Response, reqwest::Error> {
+		self.http.post(self.method_url("pinChatMessage"))
+			.form(&[
+				("chat_id", chat_id.to_string()),
+				("message_id", message_id.to_string()),
+				("disable_notification", silent.to_string()),
+			])
+			.send()
+			.await
+	}
+
+	/// Upload `content` as a file named `file_name`, with an HTML caption
+	pub async fn send_document(&self, chat_id: &str, message_thread_id: Option<i64>, caption: &str, file_name: &str, content: &str, silent: bool) -> Result<reqwest::Response, reqwest::Error> {
+		let document = Part::text(content.to_string())
+			.file_name(file_name.to_string())
+			.mime_str("text/plain")?;
+
+		let mut form = Form::new()
+			.text("chat_id", chat_id.to_string())
+			.text("caption", caption.to_string())
+			.text("parse_mode", "HTML")
+			.part("document", document);
+		if let Some(thread_id) = message_thread_id {
+			form = form.text("message_thread_id", thread_id.to_string());
+		}
+		if silent {
+			form = form.text("disable_notification", "true");
+		}
+
+		self.http.post(self.method_url("sendDocument"))
+			.multipart(form)
+			.send()
+			.await
+	}
+
+	/// Long poll for new messages, waiting up to `timeout` for one to arrive
+	pub async fn get_updates(&self, offset: i64, timeout: Duration) -> Result<reqwest::Response, reqwest::Error> {
+		self.http.post(self.method_url("getUpdates"))
+			.form(&[
+				("offset", offset.to_string()),
+				("timeout", timeout.as_secs().to_string()),
+				("allowed_updates", "[\"message\"]".to_string()),
+			])
+			.timeout(timeout + Duration::from_secs(10))
+			.send()
+			.await
+	}
+}
+
+/// A message ready to send, with the highest priority of the entries in its batch
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TelegramMessage {
+	/// The message, or the caption of a document
+	pub text: String,
+	pub priority: u8,
+	/// Contents of a `.log` file to send instead of a plain message
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub document: Option<String>,
+}
 
 #[derive(Debug)]
-struct TelegramContext {
+pub struct TelegramSink {
+	name: String,
 	chat_id: String,
-	api_key: String,
+	message_thread_id: Option<i64>,
+	notify_priority: Option<u8>,
+	pin_priority: Option<u8>,
 	flush_seconds: u16,
+	document_after: usize,
+	dedup_window: Option<Duration>,
+	line_format: LineFormat,
+	client: TelegramClient,
+	send_lock: Arc<AsyncMutex<()>>,
 }
 
-lazy_static!(
-	static ref LOG_ENTRY_BUFFER: AsyncMutex<Vec<LogEntry>> = AsyncMutex::new(Vec::new());
-	static ref PROCESSED_MESSAGE_BUFFER: AsyncMutex<Vec<String>> = AsyncMutex::new(Vec::new());
-	static ref SEND_LOCK: AsyncMutex<()> = AsyncMutex::new(());
-	static ref REQUEST_CLIENT: reqwest::Client = reqwest::Client::new();
-	static ref RETRY_FLAG: Notify = Notify::new();
-	static ref RETRY_COUNT: AsyncMutex<u64> = AsyncMutex::new(1);
-);
-static TELEGRAM_CONTEXT: OnceLock<TelegramContext> = OnceLock::new();
-
 #[derive(Deserialize)]
 struct ErrorResponse {
     // ok: Option<bool>,
@@ -41,173 +147,416 @@ struct RetryParameters {
     retry_after: Option<u64>,
 }
 
-pub fn init(settings: AppSettings, mut rx: mpsc::Receiver<LogEntry>) {
-	let flush_seconds = settings.telegram.flush_seconds.unwrap_or(5);
-	TELEGRAM_CONTEXT.set(TelegramContext {
-		chat_id: settings.telegram.chat_id,
-		api_key: settings.telegram.api_key.unwrap(),
-		flush_seconds: flush_seconds,
-	}).expect("Initialisation only occurs once");
-
-	// task to handle incoming signals
-	tokio::spawn(async move {
-		let mut signals = Signals::new(&[SIGTERM, SIGINT]).unwrap();
-		for signal in signals.forever() {
-			match signal {
-				SIGTERM | SIGINT => {
-					println!("[telegram] Received stop signal");
-					flush_log_buffer().await;
-					std::process::exit(0);
-				},
-				_ => {},
-			};
-		}
-	});
-	
-	// spawn a task to process messages as they are sent from the main task
-	tokio::spawn(async move {
-		while let Some(entry) = rx.recv().await {
-			let mut buffer = LOG_ENTRY_BUFFER.lock().await;
-			buffer.push(entry.clone());
-			if entry.priority <= 2 {
-				drop(buffer); // release the lock
-				// if this is a critical entry, flush the buffer immediately
-				tokio::spawn(async move {
-					flush_log_buffer().await;
-				});
-				continue
-			}
-			// otherwise schedule a flush if this is the first message in the buffer
-			if buffer.len() == 1 {
-				drop(buffer); // release the lock
-				let flush_seconds = match TELEGRAM_CONTEXT.get() {
-					Some(context) => context.flush_seconds,
-					None => 5,
-				};
+#[derive(Deserialize)]
+struct SentResponse {
+	result: SentMessage,
+}
 
-				tokio::spawn(async move {
-					sleep(Duration::from_secs(flush_seconds as u64)).await;
-					flush_log_buffer().await;
-				});
-			}
-		}
-	});
+#[derive(Deserialize)]
+struct SentMessage {
+	message_id: i64,
+}
 
-	// task to handle retrying failed flushes
-	tokio::spawn( async move {
-		loop {
-			RETRY_FLAG.notified().await;
-			let retry_count = RETRY_COUNT.lock().await;
-			if *retry_count > 5 {
-				// TODO: write to disk maybe?
-			};
-			sleep(Duration::from_secs(*retry_count * 2 * flush_seconds as u64)).await;
-			tokio::spawn(async move {
-				flush_log_buffer().await;
-			});
-		}
-	});
+/// Names rule groups can route to: the default chat and every named destination
+pub fn destination_names(settings: &TelegramSettings) -> Vec<String> {
+	let mut names = vec![DEFAULT_DESTINATION.to_string()];
+	names.extend(settings.destinations.keys().cloned());
+	names
+}
+
+/// Build a sink for the default chat and one for each named destination.
+/// They share one HTTP client and send lock, as Telegram rate limits per bot rather than per chat
+pub fn sinks(settings: &TelegramSettings, formats: &Formats) -> Vec<TelegramSink> {
+	let client = TelegramClient::new(settings);
+	let dedup_window = formats.dedup_window;
+	let send_lock = Arc::new(AsyncMutex::new(()));
+
+	let default_destination = TelegramDestination {
+		chat_id: settings.chat_id.clone(),
+		message_thread_id: settings.message_thread_id,
+		notify_priority: None,
+		pin_priority: None,
+	};
+
+	let mut destinations = vec![(DEFAULT_DESTINATION.to_string(), default_destination)];
+	destinations.extend(settings.destinations.iter().map(|(name, destination)| (name.clone(), destination.clone())));
 
-	println!("[telegram] initialised");
+	destinations.into_iter().map(|(name, destination)| {
+		let line_format = formats.line_format(&name);
+
+		TelegramSink {
+			name,
+			chat_id: destination.chat_id,
+			message_thread_id: destination.message_thread_id,
+			notify_priority: destination.notify_priority.or(settings.notify_priority),
+			pin_priority: destination.pin_priority.or(settings.pin_priority),
+			flush_seconds: settings.flush_seconds.unwrap_or(5),
+			document_after: settings.document_after.unwrap_or(DEFAULT_DOCUMENT_AFTER),
+			dedup_window,
+			line_format,
+			client: client.clone(),
+			send_lock: send_lock.clone(),
+		}
+	}).collect()
 }
 
-async fn send_telegram_message(message: &String, api_key: &String, chat_id: &String) -> Result<reqwest::Response, reqwest::Error> {
-	let _guard = SEND_LOCK.lock().await;
-	let response = REQUEST_CLIENT.post(&format!("https://api.telegram.org/bot{}/sendMessage", api_key))
-		.form(&[("chat_id", chat_id), ("text", message), ("parse_mode", &"HTML".to_string())])
-		.send()
-		.await;
+impl TelegramSink {
+	/// Batches below `notify_priority` are sent without a sound
+	fn is_silent(&self, priority: u8) -> bool {
+		self.notify_priority.is_some_and(|notify_priority| priority > notify_priority)
+	}
+
+	fn is_pinned(&self, priority: u8) -> bool {
+		self.pin_priority.is_some_and(|pin_priority| priority <= pin_priority)
+	}
+
+	async fn send_telegram_message(&self, message: &TelegramMessage) -> Result<reqwest::Response, reqwest::Error> {
+		let _guard = self.send_lock.clone().lock_owned().await;
+		let silent = self.is_silent(message.priority);
+		let response = match &message.document {
+			Some(document) => {
+				let file_name = format!("telelog-{}-{}.log", self.name, chrono::Local::now().format("%Y%m%d-%H%M%S"));
+				self.client.send_document(&self.chat_id, self.message_thread_id, &message.text, &file_name, document, silent).await
+			},
+			None => self.client.send_message(&self.chat_id, self.message_thread_id, &message.text, silent).await,
+		};
 
-	tokio::spawn(async move {
-		sleep(Duration::from_secs(1)).await;
-		drop(_guard);
-	});
+		tokio::spawn(async move {
+			sleep(Duration::from_secs(1)).await;
+			drop(_guard);
+		});
+
+		response
+	}
 
-	return response;
+	/// Pin a message that was just sent. The message is already delivered, so failures are only logged
+	async fn pin_telegram_message(&self, response: reqwest::Response, silent: bool) {
+		let text = response.text().await.unwrap_or_default();
+		let message_id = match serde_json::from_str::<SentResponse>(&text) {
+			Ok(sent) => sent.result.message_id,
+			Err(e) => {
+				eprintln!("[telegram] Cannot pin, failed to parse sendMessage response: {}", e);
+				return
+			}
+		};
+
+		let _guard = self.send_lock.clone().lock_owned().await;
+		match self.client.pin_message(&self.chat_id, message_id, silent).await {
+			Ok(response) if !response.status().is_success() => {
+				eprintln!("[telegram] Failed to pin message, API response {}", response.status());
+			},
+			Err(e) => eprintln!("[telegram] Failed to pin message: {}", e),
+			_ => {},
+		}
+
+		tokio::spawn(async move {
+			sleep(Duration::from_secs(1)).await;
+			drop(_guard);
+		});
+	}
 }
 
-async fn flush_log_buffer() {
-	let mut buffer = LOG_ENTRY_BUFFER.lock().await;
-	let message_list = generate_messages(&buffer);
-	buffer.clear();
-	drop(buffer); // release the lock
+impl Sink for TelegramSink {
+	type Message = TelegramMessage;
+
+	fn name(&self) -> &str {
+		&self.name
+	}
 
-	if message_list.len() == 0 {
-		eprintln!("[telegram] flush was ran, but buffer was empty");
-		return
+	fn is_default(&self) -> bool {
+		self.name == DEFAULT_DESTINATION
 	}
 
-	let (api_key, chat_id) = match TELEGRAM_CONTEXT.get() {
-		Some(context) => (context.api_key.clone(), context.chat_id.clone()),
-		None => {
-			eprintln!("[telegram] flush was ran, but context was empty");
-			return
+	fn flush_seconds(&self) -> u32 {
+		self.flush_seconds.into()
+	}
+
+	fn format(&self, entries: &[LogEntry]) -> Vec<TelegramMessage> {
+		// the whole batch rings if anything in it is important enough
+		let priority = entries.iter().map(|entry| entry.priority).min().unwrap_or(NOTICE_PRIORITY);
+		let batch = collapse_repeats(entries, self.dedup_window);
+		let messages = generate_messages(&batch, &self.line_format);
+
+		// a burst too big to read in the chat goes out as one file instead
+		if self.document_after > 0 && messages.len() > self.document_after {
+			let (caption, document) = generate_document(&batch, &self.line_format);
+			return vec![TelegramMessage { text: caption, priority, document: Some(document) }]
 		}
-	};
 
-	let mut old_unsent_messages = PROCESSED_MESSAGE_BUFFER.lock().await;
-	let mut failed_unsent_messages: Vec<String> = Vec::new();
+		messages.into_iter()
+			.map(|text| TelegramMessage { text, priority, document: None })
+			.collect()
+	}
 
-	let all_unsent_messages = flatten_messages([&old_unsent_messages, &message_list]);
+	fn combine(&self, previous: &TelegramMessage, next: &TelegramMessage) -> Option<TelegramMessage> {
+		if previous.document.is_some() || next.document.is_some() {
+			return None
+		}
+		combine_messages(&previous.text, &next.text).map(|text| TelegramMessage {
+			text,
+			priority: previous.priority.min(next.priority),
+			document: None,
+		})
+	}
 
-	// try sending all the messages
-	for message in all_unsent_messages {
-		let result = send_telegram_message(&message, &api_key, &chat_id).await;
+	fn notice(&self, text: &str) -> TelegramMessage {
+		TelegramMessage {
+			text: escape_message(&format!("⚠️ {}", text)),
+			priority: NOTICE_PRIORITY,
+			document: None,
+		}
+	}
+
+	async fn deliver(&self, message: &TelegramMessage) -> Delivery<TelegramMessage> {
+		let result = self.send_telegram_message(message).await;
 
 		if let Err(e) = result {
 			eprintln!("[telegram] Failed: {}", e);
-			failed_unsent_messages.push(message.to_string());
-			continue
+			return Delivery::Retry(message.clone())
 		}
 
 		let response = result.unwrap();
 
-		if !response.status().is_success() {
-			let status = response.status();
-			let text = response.text().await.unwrap();
-		
-			// Error handling specifics
-			match status.as_u16() {
-				429 => {
-					match serde_json::from_str(&text) as Result<ErrorResponse, JsonError> {
-						Ok(error_response) => {
-							if let Some(parameters) = error_response.parameters {
-								if let Some(retry_after) = parameters.retry_after {
-									eprintln!("[telegram] API response 429: pausing messages for {} seconds", retry_after);
-									tokio::spawn(async move {
-										let _guard = SEND_LOCK.lock().await;
-										sleep(Duration::from_secs(retry_after)).await;
-										drop(_guard);
-									});
-								}
+		if response.status().is_success() {
+			if self.is_pinned(message.priority) {
+				self.pin_telegram_message(response, self.is_silent(message.priority)).await;
+			}
+			return Delivery::Sent
+		}
+
+		let status = response.status();
+		let text = response.text().await.unwrap_or_default();
+
+		// Error handling specifics
+		match status.as_u16() {
+			429 => {
+				match serde_json::from_str(&text) as Result<ErrorResponse, JsonError> {
+					Ok(error_response) => {
+						if let Some(parameters) = error_response.parameters {
+							if let Some(retry_after) = parameters.retry_after {
+								let retry_after = Duration::from_secs(retry_after).min(MAX_PAUSE);
+								eprintln!("[telegram] API response 429: pausing messages for {} seconds", retry_after.as_secs());
+								// take the lock before returning, so the next send is queued behind the pause
+								let _guard = self.send_lock.clone().lock_owned().await;
+								tokio::spawn(async move {
+									sleep(retry_after).await;
+									drop(_guard);
+								});
 							}
 						}
-						Err(e) => {
-							eprintln!("[telegram] Failed to parse 429 response: {}", e);
-						}
 					}
+					Err(e) => {
+						eprintln!("[telegram] Failed to parse 429 response: {}", e);
+					}
+				}
 
-					failed_unsent_messages.push(message.to_string());
-				},
-				400 => {
-					println!("[telegram] API response 400. Escaping whole message for next flush... ");
-					failed_unsent_messages.push(escape_message(&message))
-				},
-				_ => {
-					println!("[telegram] API response {}: {:?}", status, text);
-					failed_unsent_messages.push(message.to_string());
+				Delivery::Retry(message.clone())
+			},
+			// entries are escaped as messages are built, so this only catches markup from elsewhere.
+			// Escaping again leaves an escaped message as it is, so a retry can't pile up escapes
+			400 if text.contains("can't parse entities") => {
+				println!("[telegram] API response 400. Escaping whole message for next flush... ");
+				let text = match message.document {
+					Some(_) => escape_html(&unescape_html(&message.text)),
+					None => reescape_message(&message.text),
+				};
+				if text == message.text {
+					return Delivery::Drop("400 Bad Request: can't parse entities, even with the whole message escaped".to_string())
 				}
+				Delivery::Retry(TelegramMessage { text, ..message.clone() })
+			},
+			_ if is_permanent_failure(status) => {
+				println!("[telegram] API response {}: {:?}", status, text);
+				Delivery::Drop(format!("{} {}", status, text))
+			},
+			_ => {
+				println!("[telegram] API response {}: {:?}", status, text);
+				Delivery::Retry(message.clone())
 			}
 		}
 	}
-	
-	let mut retry_count = RETRY_COUNT.lock().await;
-	if failed_unsent_messages.len() > 0 {
-		*retry_count *= 2;
-		RETRY_FLAG.notify_one();
-	} else {
-		*retry_count = 1;
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+	use tokio::time::Instant;
+
+	use chrono::Local;
+
+	use super::*;
+	use crate::config::{AppSettings, SpoolSettings};
+	use crate::mock_http::MockServer;
+	use crate::sink;
+
+	fn settings(api_url: &str) -> TelegramSettings {
+		let mut settings = AppSettings::default().telegram;
+		settings.chat_id = "1234".to_string();
+		settings.api_key = Some("TOKEN".to_string());
+		settings.api_url = Some(format!("{}/", api_url));
+		settings.flush_seconds = Some(1);
+		settings
+	}
+
+	fn entry(message: &str) -> LogEntry {
+		LogEntry::new(6, Local::now(), "test".to_string(), message.to_string(), BTreeMap::new())
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn batches_entries_into_one_message() {
+		let server = MockServer::start(Vec::new()).await;
+		let handle = sink::spawn(sinks(&settings(&server.url), &Formats::default()).remove(0), &SpoolSettings::default());
+
+		for message in ["first", "second", "third"] {
+			handle.send(entry(message)).await;
+		}
+		server.wait_for(1).await;
+
+		let requests = server.requests();
+		assert_eq!(requests.len(), 1);
+		assert_eq!(requests[0].path, "/botTOKEN/sendMessage");
+		assert_eq!(requests[0].headers["content-type"], "application/x-www-form-urlencoded");
+
+		let form = requests[0].form();
+		assert_eq!(form["chat_id"], "1234");
+		assert_eq!(form["parse_mode"], "HTML");
+		let text = &form["text"];
+		assert!(text.starts_with("<code>") && text.ends_with("</code>"));
+		let first = text.find("test: first").unwrap();
+		let second = text.find("test: second").unwrap();
+		let third = text.find("test: third").unwrap();
+		assert!(first < second && second < third);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn sends_markup_in_entries_literally() {
+		let server = MockServer::start(Vec::new()).await;
+		let handle = sink::spawn(sinks(&settings(&server.url), &Formats::default()).remove(0), &SpoolSettings::default());
+
+		handle.send(entry("failed: <unknown> & </code><b>x</b>")).await;
+		server.wait_for(1).await;
+
+		let requests = server.requests();
+		assert_eq!(requests.len(), 1);
+		let text = &requests[0].form()["text"];
+		assert!(text.ends_with("test: failed: &lt;unknown&gt; &amp; &lt;/code&gt;&lt;b&gt;x&lt;/b&gt;\n</code>"), "{}", text);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn sends_bursts_as_a_document() {
+		let server = MockServer::start(Vec::new()).await;
+		let handle = sink::spawn(sinks(&settings(&server.url), &Formats::default()).remove(0), &SpoolSettings::default());
+
+		for i in 0..60 {
+			let mut entry = entry(&format!("request {} failed: {}", i, "x".repeat(200)));
+			entry.identifier = if i % 3 == 0 { "nginx" } else { "app" }.to_string();
+			entry.priority = if i == 7 { 3 } else { 4 };
+			handle.send(entry).await;
+		}
+		server.wait_for(1).await;
+
+		let requests = server.requests();
+		assert_eq!(requests.len(), 1);
+		assert_eq!(requests[0].path, "/botTOKEN/sendDocument");
+		assert!(requests[0].headers["content-type"].starts_with("multipart/form-data; boundary="));
+
+		let body = String::from_utf8_lossy(&requests[0].body);
+		assert!(body.contains("filename=\"telelog-default-"));
+		assert!(body.contains("<b>60 entries</b>"));
+		assert!(body.contains("⭕\u{fe0f} err: 1, 🟡 warning: 59\napp: 40, nginx: 20"));
+		for i in 0..60 {
+			assert!(body.contains(&format!("request {} failed: ", i)));
+		}
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn pauses_sending_after_429() {
+		let server = MockServer::start(vec![
+			(429, Vec::new(), r#"{"ok":false,"error_code":429,"parameters":{"retry_after":2}}"#.to_string()),
+		]).await;
+		let telegram = sinks(&settings(&server.url), &Formats::default()).remove(0);
+		let message = TelegramMessage { text: "<code>\nrate limited\n</code>".to_string(), priority: 3, document: None };
+
+		let started = Instant::now();
+		match telegram.deliver(&message).await {
+			Delivery::Retry(retry) => assert_eq!(retry, message),
+			Delivery::Sent => panic!("429 reported as sent"),
+			Delivery::Drop(reason) => panic!("429 dropped: {}", reason),
+		}
+		assert!(matches!(telegram.deliver(&message).await, Delivery::Sent));
+
+		let requests = server.requests();
+		assert_eq!(requests.len(), 2);
+		assert!(requests[1].received.duration_since(started) >= Duration::from_secs(2));
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn escapes_message_after_400() {
+		let server = MockServer::start(vec![
+			(400, Vec::new(), r#"{"ok":false,"error_code":400,"description":"Bad Request: can't parse entities"}"#.to_string()),
+		]).await;
+		let telegram = sinks(&settings(&server.url), &Formats::default()).remove(0);
+		let message = TelegramMessage { text: "<code>\nfailed: <unknown> & \"quoted\"\n</code>".to_string(), priority: 3, document: None };
+
+		let escaped = match telegram.deliver(&message).await {
+			Delivery::Retry(escaped) => escaped,
+			Delivery::Sent => panic!("400 reported as sent"),
+			Delivery::Drop(reason) => panic!("400 dropped: {}", reason),
+		};
+		assert_eq!(escaped.text, "<code>\nfailed: &lt;unknown&gt; &amp; &quot;quoted&quot;\n</code>");
+		assert_eq!(reescape_message(&escaped.text), escaped.text);
+		assert!(matches!(telegram.deliver(&escaped).await, Delivery::Sent));
+
+		let requests = server.requests();
+		assert_eq!(requests[0].form()["text"], message.text);
+		assert_eq!(requests[1].form()["text"], escaped.text);
 	}
 
-	*old_unsent_messages = failed_unsent_messages;
+	#[tokio::test(start_paused = true)]
+	async fn silences_and_pins_by_priority() {
+		let server = MockServer::start(vec![
+			(200, Vec::new(), r#"{"ok":true,"result":{"message_id":7}}"#.to_string()),
+			(200, Vec::new(), r#"{"ok":true,"result":{"message_id":8}}"#.to_string()),
+		]).await;
+		let mut settings = settings(&server.url);
+		settings.notify_priority = Some(3);
+		settings.pin_priority = Some(2);
+		let telegram = sinks(&settings, &Formats::default()).remove(0);
+
+		let mut critical = entry("disk failed");
+		critical.priority = 2;
+		let quiet = telegram.format(&[entry("started"), entry("stopped")]);
+		let loud = telegram.format(&[entry("retrying"), critical]);
+		assert_eq!(quiet[0].priority, 6);
+		assert_eq!(loud[0].priority, 2);
+
+		for message in quiet.iter().chain(loud.iter()) {
+			assert!(matches!(telegram.deliver(message).await, Delivery::Sent));
+		}
+
+		let requests = server.requests();
+		assert_eq!(requests.len(), 3);
+		assert_eq!(requests[0].form()["disable_notification"], "true");
+		assert!(!requests[1].form().contains_key("disable_notification"));
+		assert_eq!(requests[2].path, "/botTOKEN/pinChatMessage");
+		assert_eq!(requests[2].form()["message_id"], "8");
+		assert_eq!(requests[2].form()["disable_notification"], "false");
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn drops_message_refused_for_good() {
+		let server = MockServer::start(vec![
+			(400, Vec::new(), r#"{"ok":false,"error_code":400,"description":"Bad Request: chat not found"}"#.to_string()),
+			(400, Vec::new(), r#"{"ok":false,"error_code":400,"description":"Bad Request: can't parse entities"}"#.to_string()),
+		]).await;
+		let telegram = sinks(&settings(&server.url), &Formats::default()).remove(0);
+		let message = TelegramMessage { text: "<code>\nfailed &amp; stopped\n</code>".to_string(), priority: 3, document: None };
+
+		match telegram.deliver(&message).await {
+			Delivery::Drop(reason) => assert!(reason.contains("chat not found")),
+			_ => panic!("400 chat not found not dropped"),
+		}
+		// already escaped, so escaping again can't help
+		assert!(matches!(telegram.deliver(&message).await, Delivery::Drop(_)));
+	}
 }
@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, FixedOffset, Local};
+
+use crate::config::FormatSettings;
+use crate::helpers::{colour_translate, parse_duration, Repeated};
+
+const DEFAULT_TEMPLATE: &str = "{emoji}[{timestamp}] {identifier}: {message}";
+const DEFAULT_TIMESTAMP_FORMAT: &str = "%b %d %H:%M:%S";
+
+/// syslog(3) names of priorities 0 to 7
+const PRIORITY_NAMES: [&str; 8] = ["emerg", "alert", "crit", "err", "warning", "notice", "info", "debug"];
+
+pub fn priority_name(priority: u8) -> &'static str {
+	PRIORITY_NAMES.get(priority as usize).copied().unwrap_or("unknown")
+}
+
+/// A priority given as its level, 0 to 7, or its syslog name
+pub fn parse_priority(level: &str) -> Result<u8, String> {
+	match level.parse::<u8>() {
+		Ok(priority) if priority < 8 => Ok(priority),
+		Ok(_) => Err(format!("priority {} is out of range, use 0 to 7", level)),
+		Err(_) => PRIORITY_NAMES.iter()
+			.position(|name| name.eq_ignore_ascii_case(level))
+			.map(|priority| priority as u8)
+			.ok_or_else(|| format!("unknown priority '{}', use 0 to 7 or a name like err", level)),
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+	Text(String),
+	Placeholder(String),
+}
+
+/// A template such as `{emoji}[{timestamp}] {_HOSTNAME} {identifier}: {message}`.
+/// Placeholders name a journal field or one of the values `LineFormat` provides,
+/// `{{` and `}}` are literal braces
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+	parts: Vec<Part>,
+}
+
+impl Template {
+	pub fn parse(template: &str) -> Result<Self, String> {
+		let mut parts = Vec::new();
+		let mut text = String::new();
+		let mut chars = template.chars().peekable();
+
+		while let Some(c) = chars.next() {
+			match c {
+				'{' if chars.peek() == Some(&'{') => {
+					chars.next();
+					text.push('{');
+				},
+				'}' if chars.peek() == Some(&'}') => {
+					chars.next();
+					text.push('}');
+				},
+				'{' => {
+					let mut name = String::new();
+					loop {
+						match chars.next() {
+							Some('}') => break,
+							Some(c) => name.push(c),
+							None => return Err(format!("unclosed '{{{}' in template", name)),
+						}
+					}
+					if name.is_empty() {
+						return Err("empty placeholder '{}' in template".to_string())
+					}
+					if !text.is_empty() {
+						parts.push(Part::Text(std::mem::take(&mut text)));
+					}
+					parts.push(Part::Placeholder(name));
+				},
+				'}' => return Err("unmatched '}' in template, use '}}' for a literal brace".to_string()),
+				c => text.push(c),
+			}
+		}
+		if !text.is_empty() {
+			parts.push(Part::Text(text));
+		}
+
+		Ok(Template { parts })
+	}
+
+	/// Names of the placeholders used, for validating templates
+	pub fn placeholders(&self) -> impl Iterator<Item = &str> {
+		self.parts.iter().filter_map(|part| match part {
+			Part::Placeholder(name) => Some(name.as_str()),
+			Part::Text(_) => None,
+		})
+	}
+
+	/// Fill in the template, `lookup` returns the value of a placeholder or `None` for an empty one
+	pub fn render<'a>(&self, lookup: impl Fn(&str) -> Option<String> + 'a) -> String {
+		let mut rendered = String::new();
+		for part in self.parts.iter() {
+			match part {
+				Part::Text(text) => rendered.push_str(text),
+				Part::Placeholder(name) => rendered.push_str(&lookup(name).unwrap_or_default()),
+			}
+		}
+		rendered
+	}
+}
+
+/// Timezone timestamps are shown in
+#[derive(Debug, Clone, PartialEq)]
+pub enum Zone {
+	Local,
+	Fixed(FixedOffset),
+}
+
+impl Zone {
+	/// `local` (honouring $TZ), `UTC`, or a fixed offset such as `+02:00`
+	pub fn parse(zone: &str) -> Result<Self, String> {
+		match zone {
+			"local" | "Local" => Ok(Zone::Local),
+			"UTC" | "utc" | "Z" => Ok(Zone::Fixed(FixedOffset::east_opt(0).unwrap())),
+			offset => offset.parse::<FixedOffset>()
+				.map(Zone::Fixed)
+				.map_err(|_| format!("unknown timezone '{}', use local, UTC or an offset like +02:00", zone)),
+		}
+	}
+
+	fn format(&self, timestamp: &DateTime<Local>, format: &str) -> String {
+		match self {
+			Zone::Local => timestamp.format(format).to_string(),
+			Zone::Fixed(offset) => timestamp.with_timezone(offset).format(format).to_string(),
+		}
+	}
+}
+
+/// How entries are rendered for one destination
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineFormat {
+	pub template: Template,
+	pub header: Option<Template>,
+	pub footer: Option<Template>,
+	pub timestamp_format: String,
+	pub zone: Zone,
+	/// What {emoji} shows for each priority
+	pub marks: [String; 8],
+}
+
+impl Default for LineFormat {
+	fn default() -> Self {
+		LineFormat {
+			template: Template::parse(DEFAULT_TEMPLATE).unwrap(),
+			header: None,
+			footer: None,
+			timestamp_format: DEFAULT_TIMESTAMP_FORMAT.to_string(),
+			zone: Zone::Local,
+			marks: default_marks(),
+		}
+	}
+}
+
+/// A strftime format chrono can render, formatting with an invalid one panics
+fn parse_timestamp_format(format: &str) -> Result<String, String> {
+	match StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+		true => Err(format!("invalid timestamp_format '{}', see chrono's strftime specifiers", format)),
+		false => Ok(format.to_string()),
+	}
+}
+
+fn default_marks() -> [String; 8] {
+	std::array::from_fn(|priority| colour_translate(priority as u8))
+}
+
+impl LineFormat {
+	/// The `[format]` settings, with the overrides for `destination` applied
+	pub fn new(settings: &FormatSettings, destination: &str) -> Result<Self, String> {
+		let overrides = settings.destinations.get(destination);
+		let pick = |global: &Option<String>, local: Option<&Option<String>>| -> Option<String> {
+			local.and_then(|local| local.clone()).or_else(|| global.clone())
+		};
+
+		let template = pick(&settings.template, overrides.map(|o| &o.template));
+		let header = pick(&settings.header, overrides.map(|o| &o.header));
+		let footer = pick(&settings.footer, overrides.map(|o| &o.footer));
+		let timestamp_format = pick(&settings.timestamp_format, overrides.map(|o| &o.timestamp_format));
+		let timezone = pick(&settings.timezone, overrides.map(|o| &o.timezone));
+
+		let mut marks = default_marks();
+		for (level, mark) in settings.priority.iter().chain(overrides.iter().flat_map(|o| o.priority.iter())) {
+			marks[parse_priority(level)? as usize] = mark.clone();
+		}
+
+		Ok(LineFormat {
+			template: Template::parse(template.as_deref().unwrap_or(DEFAULT_TEMPLATE))?,
+			header: header.as_deref().map(Template::parse).transpose()?,
+			footer: footer.as_deref().map(Template::parse).transpose()?,
+			timestamp_format: parse_timestamp_format(timestamp_format.as_deref().unwrap_or(DEFAULT_TIMESTAMP_FORMAT))?,
+			zone: timezone.as_deref().map(Zone::parse).transpose()?.unwrap_or(Zone::Local),
+			marks,
+		})
+	}
+
+	/// The configured mark of a priority, such as an emoji or a label like ERR
+	pub fn mark(&self, priority: u8) -> String {
+		match self.marks.get(priority as usize) {
+			Some(mark) => mark.clone(),
+			None => colour_translate(priority),
+		}
+	}
+
+	pub fn timestamp(&self, timestamp: &DateTime<Local>) -> String {
+		self.zone.format(timestamp, &self.timestamp_format)
+	}
+
+	/// Render one line. Repeated entries show the time of the first and last repeat and their count
+	pub fn line(&self, repeated: &Repeated) -> String {
+		let entry = repeated.entry;
+		let line = self.template.render(|name| match name {
+			"emoji" => Some(self.mark(entry.priority)),
+			"priority" => Some(entry.priority.to_string()),
+			"priority_name" => Some(priority_name(entry.priority).to_string()),
+			"timestamp" if repeated.count > 1 => Some(format!("{} - {}", self.timestamp(&entry.timestamp), self.timestamp(&repeated.last))),
+			"timestamp" => Some(self.timestamp(&entry.timestamp)),
+			"identifier" => Some(entry.identifier.clone()),
+			"message" => Some(entry.message.clone()),
+			"count" => Some(repeated.count.to_string()),
+			field => entry.get_field(field).ok(),
+		});
+
+		match repeated.count {
+			1 => line,
+			count => format!("{} (repeated {} times)", line, count),
+		}
+	}
+
+	/// Render the header or footer of a batch
+	pub fn batch_line(&self, template: &Template, batch: &[Repeated]) -> String {
+		template.render(|name| match name {
+			"count" => Some(batch.iter().map(|repeated| repeated.count).sum::<usize>().to_string()),
+			"first" => batch.first().map(|repeated| self.timestamp(&repeated.entry.timestamp)),
+			"last" => batch.iter().map(|repeated| repeated.last).max().map(|last| self.timestamp(&last)),
+			"hostname" => batch.first().and_then(|repeated| repeated.entry.get_field("_HOSTNAME").ok()),
+			_ => None,
+		})
+	}
+}
+
+/// The `[format]` section compiled once for the sinks: each destination's line format and the dedup window
+#[derive(Debug, Clone, Default)]
+pub struct Formats {
+	default: LineFormat,
+	destinations: HashMap<String, LineFormat>,
+	pub dedup_window: Option<Duration>,
+}
+
+impl Formats {
+	/// Compile the line format of every destination and the dedup window, failing on the first invalid one
+	pub fn new(settings: &FormatSettings) -> Result<Self, String> {
+		let default = LineFormat::new(settings, "").map_err(|e| format!("[format] {}", e))?;
+		let mut names: Vec<&String> = settings.destinations.keys().collect();
+		names.sort();
+		let mut destinations: HashMap<String, LineFormat> = HashMap::new();
+		for name in names {
+			let line_format = LineFormat::new(settings, name).map_err(|e| format!("[format.destinations.{}] {}", name, e))?;
+			destinations.insert(name.clone(), line_format);
+		}
+
+		Ok(Formats {
+			default,
+			destinations,
+			dedup_window: match settings.dedup_window.as_deref() {
+				None | Some("0") => None,
+				Some(window) => Some(parse_duration(window).ok_or_else(|| format!("[format] dedup_window: invalid duration '{}', use e.g. 30s or 5m", window))?),
+			},
+		})
+	}
+
+	/// The line format of a destination, its own if it has overrides
+	pub fn line_format(&self, destination: &str) -> LineFormat {
+		self.destinations.get(destination).unwrap_or(&self.default).clone()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+
+	use chrono::TimeZone;
+
+	use super::*;
+	use crate::config::FormatOverride;
+	use crate::journal::LogEntry;
+
+	fn entry(priority: u8, message: &str) -> LogEntry {
+		let timestamp = Local.from_utc_datetime(&chrono::NaiveDate::from_ymd_opt(2024, 3, 1).unwrap().and_hms_opt(12, 30, 5).unwrap());
+		let fields = BTreeMap::from([("_HOSTNAME".to_string(), "web1".to_string())]);
+		LogEntry::new(priority, timestamp, "nginx".to_string(), message.to_string(), fields)
+	}
+
+	fn once(entry: &LogEntry) -> Repeated<'_> {
+		Repeated { entry, count: 1, last: entry.timestamp }
+	}
+
+	#[test]
+	fn parses_placeholders_and_literal_braces() {
+		let template = Template::parse("{{{identifier}}} {_HOSTNAME}: {message}").unwrap();
+		assert_eq!(template.placeholders().collect::<Vec<_>>(), vec!["identifier", "_HOSTNAME", "message"]);
+		let rendered = template.render(|name| match name {
+			"identifier" => Some("sshd".to_string()),
+			"message" => Some("hello".to_string()),
+			_ => None,
+		});
+		assert_eq!(rendered, "{sshd} : hello");
+
+		assert!(Template::parse("{message").is_err());
+		assert!(Template::parse("{}").is_err());
+		assert!(Template::parse("message}").is_err());
+	}
+
+	#[test]
+	fn parses_zones() {
+		assert_eq!(Zone::parse("local"), Ok(Zone::Local));
+		assert_eq!(Zone::parse("UTC"), Ok(Zone::Fixed(FixedOffset::east_opt(0).unwrap())));
+		assert_eq!(Zone::parse("+02:00"), Ok(Zone::Fixed(FixedOffset::east_opt(2 * 3600).unwrap())));
+		assert!(Zone::parse("Europe/Nowhere").is_err());
+	}
+
+	#[test]
+	fn renders_lines_in_the_configured_zone() {
+		let settings = FormatSettings {
+			template: Some("{priority_name} {emoji}[{timestamp}] {_HOSTNAME} {identifier}: {message} {MISSING}".to_string()),
+			timestamp_format: Some("%Y-%m-%d %H:%M:%S %z".to_string()),
+			timezone: Some("+02:00".to_string()),
+			priority: [("err".to_string(), "ERR".to_string())].into(),
+			..Default::default()
+		};
+		let format = LineFormat::new(&settings, "default").unwrap();
+
+		let failed = entry(3, "upstream timed out");
+		assert_eq!(format.line(&once(&failed)), "err ERR[2024-03-01 14:30:05 +0200] web1 nginx: upstream timed out ");
+
+		let repeated = Repeated { entry: &failed, count: 3, last: failed.timestamp + chrono::Duration::minutes(2) };
+		assert_eq!(format.line(&repeated), "err ERR[2024-03-01 14:30:05 +0200 - 2024-03-01 14:32:05 +0200] web1 nginx: upstream timed out  (repeated 3 times)");
+	}
+
+	#[test]
+	fn applies_destination_overrides_to_headers_and_footers() {
+		let settings = FormatSettings {
+			header: Some("{count} entries".to_string()),
+			timezone: Some("UTC".to_string()),
+			destinations: [("ops".to_string(), FormatOverride {
+				header: Some("{count} entries from {hostname}, {first} to {last}".to_string()),
+				footer: Some("-- end --".to_string()),
+				timestamp_format: Some("%H:%M".to_string()),
+				..Default::default()
+			})].into(),
+			..Default::default()
+		};
+
+		let first = entry(6, "started");
+		let mut second = entry(6, "stopped");
+		second.timestamp += chrono::Duration::minutes(5);
+		let batch = vec![once(&first), Repeated { entry: &second, count: 2, last: second.timestamp + chrono::Duration::minutes(1) }];
+
+		let default = LineFormat::new(&settings, "default").unwrap();
+		assert_eq!(default.batch_line(default.header.as_ref().unwrap(), &batch), "3 entries");
+		assert_eq!(default.footer, None);
+
+		let ops = LineFormat::new(&settings, "ops").unwrap();
+		assert_eq!(ops.batch_line(ops.header.as_ref().unwrap(), &batch), "3 entries from web1, 12:30 to 12:36");
+		assert_eq!(ops.batch_line(ops.footer.as_ref().unwrap(), &batch), "-- end --");
+		assert_eq!(ops.zone, Zone::Fixed(FixedOffset::east_opt(0).unwrap()));
+	}
+
+	#[test]
+	fn rejects_invalid_settings() {
+		let invalid = |settings: FormatSettings| LineFormat::new(&settings, "default").unwrap_err();
+
+		assert!(invalid(FormatSettings { timestamp_format: Some("%H:%M %Q".to_string()), ..Default::default() }).contains("timestamp_format"));
+		assert!(invalid(FormatSettings { timestamp_format: Some("%".to_string()), ..Default::default() }).contains("timestamp_format"));
+		assert!(invalid(FormatSettings { template: Some("{message".to_string()), ..Default::default() }).contains("unclosed"));
+		assert!(invalid(FormatSettings { priority: [("loud".to_string(), "!".to_string())].into(), ..Default::default() }).contains("unknown priority"));
+
+		let settings = FormatSettings {
+			destinations: [("ops".to_string(), FormatOverride { timestamp_format: Some("%Q".to_string()), ..Default::default() })].into(),
+			..Default::default()
+		};
+		assert!(LineFormat::new(&settings, "default").is_ok());
+		assert!(LineFormat::new(&settings, "ops").is_err());
+		assert!(Formats::new(&settings).unwrap_err().starts_with("[format.destinations.ops]"));
+	}
+
+	#[test]
+	fn picks_each_destinations_own_format() {
+		let settings = FormatSettings {
+			template: Some("{message}".to_string()),
+			destinations: [("ops".to_string(), FormatOverride { template: Some("{identifier}".to_string()), ..Default::default() })].into(),
+			..Default::default()
+		};
+		let formats = Formats::new(&settings).unwrap();
+		let entry = entry(3, "failed");
+		assert_eq!(formats.line_format("ops").line(&once(&entry)), "nginx");
+		assert_eq!(formats.line_format("telegram").line(&once(&entry)), "failed");
+	}
+
+	#[test]
+	fn dedup_is_opt_in_and_checked() {
+		let window = |window: Option<&str>| Formats::new(&FormatSettings { dedup_window: window.map(str::to_string), ..Default::default() });
+		assert_eq!(window(None).unwrap().dedup_window, None);
+		assert_eq!(window(Some("0")).unwrap().dedup_window, None);
+		assert_eq!(window(Some("5m")).unwrap().dedup_window, Some(Duration::from_secs(300)));
+		assert!(window(Some("five minutes")).unwrap_err().contains("dedup_window"));
+	}
+}
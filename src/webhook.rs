@@ -0,0 +1,409 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Local;
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::sleep;
+
+use crate::config::JsonWebhookSettings;
+use crate::helpers::{parse_duration, MAX_PAUSE};
+use crate::journal::LogEntry;
+use crate::sink::{Delivery, Sink};
+use crate::template::priority_name;
+
+/// Name of the destination built from the top level `webhook.url`
+const DEFAULT_DESTINATION: &str = "webhook";
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest wait between attempts of one delivery, the flush behind it waits as well
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Most entries posted in one request, longer batches are split
+const MAX_ENTRIES: usize = 500;
+
+/// Posts batches to any HTTP endpoint as a JSON array of entries, each with all its journal fields
+#[derive(Debug)]
+pub struct JsonWebhookSink {
+	name: String,
+	url: String,
+	headers: HeaderMap,
+	hmac_secret: Option<String>,
+	signature_header: HeaderName,
+	retries: u32,
+	backoff: Duration,
+	flush_seconds: u16,
+	http: reqwest::Client,
+	send_lock: Arc<AsyncMutex<()>>,
+}
+
+/// Names rule groups can route to: the default endpoint, if set, and every named destination
+pub fn destination_names(settings: &JsonWebhookSettings) -> Vec<String> {
+	let mut names: Vec<String> = settings.url.iter().map(|_| DEFAULT_DESTINATION.to_string()).collect();
+	names.extend(settings.destinations.keys().cloned());
+	names
+}
+
+/// Parse configured headers, skipping any that are not valid HTTP
+fn header_map(name: &str, headers: &[(&String, &String)], bearer_token: Option<&String>) -> HeaderMap {
+	let mut map = HeaderMap::new();
+	for (header, value) in headers {
+		match (HeaderName::from_bytes(header.as_bytes()), HeaderValue::from_str(value)) {
+			(Ok(header), Ok(value)) => {
+				map.insert(header, value);
+			},
+			_ => println!("[webhook] Skipping invalid header '{}' of {}", header, name),
+		}
+	}
+	if let Some(token) = bearer_token {
+		match HeaderValue::from_str(&format!("Bearer {}", token)) {
+			Ok(mut value) => {
+				value.set_sensitive(true);
+				map.insert(reqwest::header::AUTHORIZATION, value);
+			},
+			Err(_) => println!("[webhook] Skipping invalid bearer_token of {}", name),
+		}
+	}
+	map
+}
+
+/// Build a sink for the default endpoint and one for each named destination
+pub fn sinks(settings: &JsonWebhookSettings) -> Vec<JsonWebhookSink> {
+	let timeout = settings.timeout.as_deref().and_then(parse_duration).unwrap_or(DEFAULT_TIMEOUT);
+	let http = match reqwest::Client::builder().timeout(timeout).build() {
+		Ok(http) => http,
+		Err(e) => {
+			println!("[webhook] Failed to build HTTP client, not posting: {}", e);
+			return Vec::new()
+		}
+	};
+	let signature_header = match settings.signature_header.as_deref().map(|header| HeaderName::from_bytes(header.as_bytes())) {
+		Some(Ok(header)) => header,
+		Some(Err(_)) => {
+			println!("[webhook] Invalid signature_header, using X-Telelog-Signature");
+			HeaderName::from_static("x-telelog-signature")
+		},
+		None => HeaderName::from_static("x-telelog-signature"),
+	};
+
+	let mut endpoints: Vec<(String, String, HeaderMap, Option<String>)> = Vec::new();
+	if let Some(url) = &settings.url {
+		let headers: Vec<(&String, &String)> = settings.headers.iter().collect();
+		let headers = header_map(DEFAULT_DESTINATION, &headers, settings.bearer_token.as_ref());
+		endpoints.push((DEFAULT_DESTINATION.to_string(), url.clone(), headers, settings.hmac_secret.clone()));
+	}
+	for (name, destination) in settings.destinations.iter() {
+		// the destination's own headers come last, so they replace shared ones of the same name
+		let headers: Vec<(&String, &String)> = settings.headers.iter().chain(destination.headers.iter()).collect();
+		let bearer_token = destination.bearer_token.as_ref().or(settings.bearer_token.as_ref());
+		let headers = header_map(name, &headers, bearer_token);
+		let hmac_secret = destination.hmac_secret.clone().or_else(|| settings.hmac_secret.clone());
+		endpoints.push((name.clone(), destination.url.clone(), headers, hmac_secret));
+	}
+
+	endpoints.into_iter().map(|(name, url, headers, hmac_secret)| JsonWebhookSink {
+		name,
+		url,
+		headers,
+		hmac_secret,
+		signature_header: signature_header.clone(),
+		retries: settings.retries.unwrap_or(DEFAULT_RETRIES),
+		backoff: settings.backoff.as_deref().and_then(parse_duration).unwrap_or(DEFAULT_BACKOFF),
+		flush_seconds: settings.flush_seconds.unwrap_or(5),
+		http: http.clone(),
+		// endpoints are rate limited one by one
+		send_lock: Arc::new(AsyncMutex::new(())),
+	}).collect()
+}
+
+/// Hex HMAC-SHA256 of `body`, as sent in the signature header after `sha256=`
+pub fn signature(secret: &str, body: &[u8]) -> String {
+	let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+	mac.update(body);
+	hex::encode(mac.finalize().into_bytes())
+}
+
+fn entry_json(entry: &LogEntry) -> Value {
+	json!({
+		"priority": entry.priority,
+		"priority_name": priority_name(entry.priority),
+		"timestamp": entry.timestamp.to_rfc3339(),
+		"identifier": entry.identifier,
+		"message": entry.message,
+		"cursor": entry.cursor,
+		"fields": entry.raw_fields(),
+	})
+}
+
+/// Whether a failed request is worth repeating straight away
+enum Failure {
+	Transient,
+	/// Rate limited, left to the sink's retry queue
+	RateLimited,
+	/// Refused for good, with the reason
+	Refused(String),
+}
+
+impl JsonWebhookSink {
+	async fn post(&self, body: String) -> Result<reqwest::Response, reqwest::Error> {
+		let _guard = self.send_lock.clone().lock_owned().await;
+		let mut request = self.http.post(&self.url)
+			.headers(self.headers.clone())
+			.header(reqwest::header::CONTENT_TYPE, "application/json");
+		if let Some(secret) = &self.hmac_secret {
+			request = request.header(self.signature_header.clone(), format!("sha256={}", signature(secret, body.as_bytes())));
+		}
+		request.body(body).send().await
+	}
+
+	async fn attempt(&self, body: &str) -> Result<(), Failure> {
+		let response = match self.post(body.to_string()).await {
+			Ok(response) => response,
+			Err(e) => {
+				eprintln!("[webhook] Failed: {}", e);
+				return Err(Failure::Transient)
+			}
+		};
+
+		let status = response.status();
+		if status.is_success() {
+			return Ok(())
+		}
+
+		let retry_after = response.headers().get("retry-after")
+			.and_then(|value| value.to_str().ok())
+			.and_then(|value| value.parse::<u64>().ok());
+		let text = response.text().await.unwrap_or_default();
+		println!("[webhook] Response {}: {:?}", status, text);
+
+		match status.as_u16() {
+			429 => {
+				let retry_after = Duration::from_secs(retry_after.unwrap_or(self.flush_seconds as u64)).min(MAX_PAUSE);
+				eprintln!("[webhook] Response 429: pausing requests for {} seconds", retry_after.as_secs());
+				// take the lock before returning, so the next post is queued behind the pause
+				let _guard = self.send_lock.clone().lock_owned().await;
+				tokio::spawn(async move {
+					sleep(retry_after).await;
+					drop(_guard);
+				});
+				Err(Failure::RateLimited)
+			},
+			408 | 500..=599 => Err(Failure::Transient),
+			_ => Err(Failure::Refused(format!("{} {}", status, text))),
+		}
+	}
+}
+
+impl Sink for JsonWebhookSink {
+	type Message = Vec<Value>;
+
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn is_default(&self) -> bool {
+		self.name == DEFAULT_DESTINATION
+	}
+
+	fn flush_seconds(&self) -> u32 {
+		self.flush_seconds.into()
+	}
+
+	fn format(&self, entries: &[LogEntry]) -> Vec<Vec<Value>> {
+		entries.chunks(MAX_ENTRIES).map(|chunk| chunk.iter().map(entry_json).collect()).collect()
+	}
+
+	fn combine(&self, previous: &Vec<Value>, next: &Vec<Value>) -> Option<Vec<Value>> {
+		if previous.len() + next.len() > MAX_ENTRIES {
+			return None
+		}
+		Some(previous.iter().chain(next.iter()).cloned().collect())
+	}
+
+	fn notice(&self, text: &str) -> Vec<Value> {
+		vec![json!({
+			"priority": 4,
+			"priority_name": priority_name(4),
+			"timestamp": Local::now().to_rfc3339(),
+			"identifier": "telelog",
+			"message": text,
+			"cursor": null,
+			"fields": {},
+		})]
+	}
+
+	async fn deliver(&self, message: &Vec<Value>) -> Delivery<Vec<Value>> {
+		let body = Value::Array(message.clone()).to_string();
+
+		let mut backoff = self.backoff.min(MAX_BACKOFF);
+		for attempt in 0..=self.retries {
+			if attempt > 0 {
+				sleep(backoff).await;
+				backoff = backoff.saturating_mul(2).min(MAX_BACKOFF);
+			}
+			match self.attempt(&body).await {
+				Ok(()) => return Delivery::Sent,
+				Err(Failure::Transient) => continue,
+				Err(Failure::RateLimited) => break,
+				Err(Failure::Refused(reason)) => return Delivery::Drop(reason),
+			}
+		}
+
+		Delivery::Retry(message.clone())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+	use tokio::time::Instant;
+
+	use super::*;
+	use crate::config::{JsonWebhookDestination, SpoolSettings};
+	use crate::mock_http::MockServer;
+	use crate::sink;
+
+	fn settings(url: &str) -> JsonWebhookSettings {
+		JsonWebhookSettings {
+			url: Some(format!("{}/hooks/telelog", url)),
+			headers: [("X-Source".to_string(), "telelog".to_string())].into(),
+			bearer_token: Some("token".to_string()),
+			hmac_secret: Some("secret".to_string()),
+			signature_header: None,
+			timeout: None,
+			retries: Some(1),
+			backoff: Some("1s".to_string()),
+			flush_seconds: Some(1),
+			destinations: [("incidents".to_string(), JsonWebhookDestination {
+				url: format!("{}/incidents", url),
+				headers: [("X-Source".to_string(), "journal".to_string())].into(),
+				bearer_token: None,
+				hmac_secret: None,
+			})].into(),
+		}
+	}
+
+	fn entry(message: &str) -> LogEntry {
+		let fields = [
+			("_HOSTNAME".to_string(), "web1".to_string()),
+			("_SYSTEMD_UNIT".to_string(), "nginx.service".to_string()),
+		].into_iter().collect::<BTreeMap<_, _>>();
+		LogEntry::new(3, Local::now(), "nginx".to_string(), message.to_string(), fields)
+	}
+
+	fn default_sink(settings: &JsonWebhookSettings) -> JsonWebhookSink {
+		sinks(settings).into_iter().find(|sink| sink.is_default()).unwrap()
+	}
+
+	#[test]
+	fn signs_with_hmac_sha256() {
+		// RFC 4231, test case 2
+		assert_eq!(signature("Jefe", b"what do ya want for nothing?"), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn posts_entries_with_their_journal_fields() {
+		let server = MockServer::start(Vec::new()).await;
+		let handle = sink::spawn(default_sink(&settings(&server.url)), &SpoolSettings::default());
+
+		handle.send(entry("upstream timed out")).await;
+		handle.send(entry("\"quoted\" <b>markup</b>")).await;
+		server.wait_for(1).await;
+
+		let requests = server.requests();
+		assert_eq!(requests.len(), 1);
+		assert_eq!(requests[0].path, "/hooks/telelog");
+		assert_eq!(requests[0].headers["content-type"], "application/json");
+		assert_eq!(requests[0].headers["authorization"], "Bearer token");
+		assert_eq!(requests[0].headers["x-source"], "telelog");
+		assert_eq!(requests[0].headers["x-telelog-signature"], format!("sha256={}", signature("secret", &requests[0].body)));
+
+		let entries = requests[0].json();
+		let entries = entries.as_array().unwrap();
+		assert_eq!(entries.len(), 2);
+		assert_eq!(entries[0]["priority"], 3);
+		assert_eq!(entries[0]["priority_name"], "err");
+		assert_eq!(entries[0]["identifier"], "nginx");
+		assert_eq!(entries[0]["message"], "upstream timed out");
+		assert_eq!(entries[0]["fields"]["_HOSTNAME"], "web1");
+		assert_eq!(entries[0]["fields"]["_SYSTEMD_UNIT"], "nginx.service");
+		assert_eq!(entries[1]["message"], "\"quoted\" <b>markup</b>");
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn destinations_override_shared_settings() {
+		let server = MockServer::start(Vec::new()).await;
+		let incidents = sinks(&settings(&server.url)).into_iter().find(|sink| sink.name() == "incidents").unwrap();
+		assert!(!incidents.is_default());
+
+		assert!(matches!(incidents.deliver(&incidents.format(&[entry("down")]).remove(0)).await, Delivery::Sent));
+
+		let requests = server.requests();
+		assert_eq!(requests[0].path, "/incidents");
+		assert_eq!(requests[0].headers["x-source"], "journal");
+		assert_eq!(requests[0].headers["authorization"], "Bearer token");
+		assert!(requests[0].headers.contains_key("x-telelog-signature"));
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn retries_with_backoff_then_queues() {
+		let server = MockServer::start(vec![
+			(500, Vec::new(), "oops".to_string()),
+			(503, Vec::new(), "busy".to_string()),
+		]).await;
+		let webhook = default_sink(&settings(&server.url));
+		let message = webhook.format(&[entry("flaky")]).remove(0);
+
+		let started = Instant::now();
+		match webhook.deliver(&message).await {
+			Delivery::Retry(retry) => assert_eq!(retry, message),
+			Delivery::Sent => panic!("5xx reported as sent"),
+			Delivery::Drop(reason) => panic!("5xx dropped: {}", reason),
+		}
+		assert!(started.elapsed() >= Duration::from_secs(1));
+		assert!(matches!(webhook.deliver(&message).await, Delivery::Sent));
+
+		let requests = server.requests();
+		assert_eq!(requests.len(), 3);
+		assert!(requests[1].received.duration_since(requests[0].received) >= Duration::from_secs(1));
+		assert_eq!(requests[0].body, requests[2].body);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn drops_refused_message_without_retrying() {
+		let server = MockServer::start(vec![
+			(422, Vec::new(), "unprocessable".to_string()),
+		]).await;
+		let webhook = default_sink(&settings(&server.url));
+		let message = webhook.format(&[entry("refused")]).remove(0);
+
+		match webhook.deliver(&message).await {
+			Delivery::Drop(reason) => assert!(reason.contains("unprocessable")),
+			_ => panic!("422 not dropped"),
+		}
+		assert_eq!(server.requests().len(), 1);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn caps_the_backoff_between_attempts() {
+		let server = MockServer::start((0..40).map(|_| (500, Vec::new(), "oops".to_string())).collect()).await;
+		let mut settings = settings(&server.url);
+		settings.retries = Some(39);
+		settings.backoff = Some("1d".to_string());
+		let webhook = default_sink(&settings);
+
+		assert!(matches!(webhook.deliver(&webhook.format(&[entry("down")]).remove(0)).await, Delivery::Retry(_)));
+
+		let requests = server.requests();
+		assert_eq!(requests.len(), 40);
+		// the paused clock also skips over idle client timers, so allow some slack on top of the cap
+		for pair in requests.windows(2) {
+			assert!(pair[1].received.duration_since(pair[0].received) < MAX_BACKOFF * 2);
+		}
+	}
+}